@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use rocket::config::Config;
+use rocket::response::status;
+use rocket_contrib::json::JsonValue;
+
+use crate::model::Feedback;
+use crate::{traffic, utils};
+
+#[derive(Clone, Copy)]
+struct Factor {
+    factor: f64,
+    samples: usize,
+}
+
+// Learns duration correction factors per provider, region and local hour
+// from completed trips appended to a JSON lines file.
+#[derive(Clone)]
+pub struct Calibration {
+    path: String,
+    // serializes appends to the feedback file
+    writer: Arc<Mutex<()>>,
+    factors: Arc<RwLock<HashMap<String, Factor>>>,
+    min_samples: usize,
+    min_factor: f64,
+    max_factor: f64,
+    utc_offset: i64,
+}
+
+impl Calibration {
+    pub fn from_config(config: &Config) -> Self {
+        let path = match config.get_str("feedback_file") {
+            Ok(p) => p.to_string(),
+            _ => String::from(""),
+        };
+
+        let min_samples = match config.get_int("calibration_min_samples") {
+            Ok(n) if n > 0 => n as usize,
+            _ => 20,
+        };
+
+        let (min_factor, max_factor) = match config.get_slice("calibration_factor_bounds") {
+            Ok(b) if b.len() == 2 => (b[0].as_float().unwrap_or(0.5), b[1].as_float().unwrap_or(2.0)),
+            _ => (0.5, 2.0),
+        };
+
+        let utc_offset = match config.get_int("utc_offset") {
+            Ok(o) => o,
+            _ => 7,
+        };
+
+        Self {
+            path,
+            writer: Arc::new(Mutex::new(())),
+            factors: Arc::new(RwLock::new(HashMap::new())),
+            min_samples,
+            min_factor,
+            max_factor,
+            utc_offset,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.path.is_empty()
+    }
+
+    // Appends a trip, the predicted duration is stored without the
+    // calibration it was returned with so factors don't compound.
+    pub fn record(&self, mut feedback: Feedback) -> Result<(), String> {
+        if !self.is_enabled() {
+            return Err(String::from("feedback is disabled"));
+        }
+
+        if feedback.provider.is_empty() {
+            return Err(String::from("missing provider"));
+        }
+
+        if feedback.predicted_duration <= 0.0 || feedback.actual_duration <= 0.0 {
+            return Err(String::from("durations must be positive"));
+        }
+
+        if let Some(f) = feedback.calibration_factor.take() {
+            if f > 0.0 {
+                feedback.predicted_duration /= f;
+            }
+        }
+
+        let line = serde_json::to_string(&feedback).map_err(|e| e.to_string())?;
+        let _guard = match self.writer.lock() {
+            Ok(g) => g,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path.as_str())
+            .map_err(|e| e.to_string())?;
+        writeln!(file, "{}", line).map_err(|e| e.to_string())
+    }
+
+    // Recomputes every factor from the feedback file, a bucket needs
+    // min_samples trips and its factor is clamped to the bounds.
+    pub fn calibrate(&self) -> Result<usize, String> {
+        let content = match fs::read_to_string(self.path.as_str()) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::from(""),
+            Err(e) => return Err(e.to_string()),
+        };
+
+        // predicted and actual duration sums per bucket
+        let mut sums: HashMap<String, (f64, f64, usize)> = HashMap::new();
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            let feedback: Feedback = match serde_json::from_str(line) {
+                Ok(f) => f,
+                Err(err) => {
+                    println!("skip feedback line: {}", err);
+                    continue;
+                }
+            };
+
+            let hour = utils::hour_at(feedback.timestamp, self.utc_offset);
+            let entry = sums.entry(key(feedback.provider.as_str(), feedback.region.as_deref(), hour)).or_insert((0.0, 0.0, 0));
+            entry.0 += feedback.predicted_duration;
+            entry.1 += feedback.actual_duration;
+            entry.2 += 1;
+        }
+
+        let mut factors: HashMap<String, Factor> = HashMap::new();
+        for (bucket, (predicted, actual, samples)) in sums {
+            if samples < self.min_samples || predicted <= 0.0 {
+                continue;
+            }
+
+            factors.insert(bucket, Factor {
+                factor: (actual / predicted).max(self.min_factor).min(self.max_factor),
+                samples,
+            });
+        }
+
+        let count = factors.len();
+        match self.factors.write() {
+            Ok(mut f) => *f = factors,
+            Err(poisoned) => *poisoned.into_inner() = factors,
+        }
+        Ok(count)
+    }
+
+    pub fn spawn_calibration(&self, interval: Duration) {
+        let calibration = self.clone();
+        thread::spawn(move || loop {
+            match calibration.calibrate() {
+                Ok(n) => println!("calibrated {} feedback buckets", n),
+                Err(err) => println!("calibrate from {} fail: {}", calibration.path, err),
+            }
+            thread::sleep(interval);
+        });
+    }
+
+    fn factor(&self, provider: &str, region: Option<&str>, hour: u32) -> Option<Factor> {
+        let factors = match self.factors.read() {
+            Ok(f) => f,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        factors.get(&key(provider, region, hour)).copied()
+    }
+
+    // Multiplies route durations by the factor learnt for the provider,
+    // region and departure hour.
+    pub fn apply(&self, source: &str, region: Option<&str>, depart_at: Option<u64>, mut result: status::Custom<JsonValue>) -> status::Custom<JsonValue> {
+        if result.0.code >= 400 {
+            return result;
+        }
+
+        let hour = utils::hour_at(depart_at.unwrap_or_else(utils::now_secs), self.utc_offset);
+        let factor = match self.factor(source, region, hour) {
+            Some(f) => f,
+            None => return result,
+        };
+
+        if let Some(routes) = result.1["routes"].as_array_mut() {
+            for route in routes.iter_mut() {
+                if let Some(duration) = route["duration"].as_f64() {
+                    route["duration"] = (duration * factor.factor).round().into();
+                }
+                traffic::scale_parts(route, factor.factor);
+            }
+        }
+
+        result.1["calibration"] = json!({
+            "factor": factor.factor,
+            "samples": factor.samples,
+        }).into();
+        result
+    }
+
+    pub fn status(&self) -> JsonValue {
+        let factors = match self.factors.read() {
+            Ok(f) => f,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let mut buckets: Vec<serde_json::Value> = Vec::new();
+        for (bucket, f) in factors.iter() {
+            buckets.push(json!({
+                "bucket": bucket,
+                "factor": f.factor,
+                "samples": f.samples,
+            }).into());
+        }
+
+        json!({
+            "min_samples": self.min_samples,
+            "factors": buckets,
+        })
+    }
+}
+
+fn key(provider: &str, region: Option<&str>, hour: u32) -> String {
+    format!("{}/{}/{}", provider, region.unwrap_or("default"), hour)
+}
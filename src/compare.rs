@@ -0,0 +1,140 @@
+use std::thread;
+use std::time::Instant;
+
+use rocket::http::Status;
+use rocket::response::status;
+use rocket_contrib::json::JsonValue;
+use serde_json::Value;
+
+use crate::{similarity, utils};
+use crate::service::{self, Context, Upstreams};
+
+struct Outcome {
+    source: String,
+    result: status::Custom<JsonValue>,
+    latency_ms: u64,
+}
+
+// Queries every configured provider concurrently and reports each route
+// next to its deltas against the baseline provider.
+pub fn compare(upstreams: &Upstreams, boundary: &str, baseline: Option<String>, ctx: &Context) -> status::Custom<JsonValue> {
+    let sources = upstreams.configured();
+    if let Some(b) = &baseline {
+        if !upstreams.knows(b.as_str()) {
+            return utils::format_response("missing or wrong baseline param", Status::BadRequest);
+        }
+
+        if !sources.contains(b) {
+            return utils::format_response(format!("baseline {} is not configured", b).as_str(), Status::BadRequest);
+        }
+    }
+
+    let mut handles = Vec::new();
+    for source in sources {
+        let upstreams = upstreams.clone();
+        let boundary = boundary.to_string();
+        let client = ctx.client.clone();
+        let deadline = *ctx.deadline;
+        let pool = ctx.pool.clone();
+        let offline = ctx.offline.clone();
+
+        handles.push(thread::spawn(move || {
+            let started = Instant::now();
+            let result = match service::call_provider(source.as_str(), &upstreams, boundary.as_str(), &pool, &offline, &client, &deadline) {
+                Some(r) => r,
+                None => utils::format_response("unknown source", Status::BadRequest),
+            };
+
+            Outcome {
+                source,
+                result,
+                latency_ms: started.elapsed().as_millis() as u64,
+            }
+        }));
+    }
+
+    let mut outcomes: Vec<Outcome> = Vec::new();
+    for handle in handles {
+        match handle.join() {
+            Ok(mut outcome) => {
+                let source = outcome.source.as_str();
+                let result = ctx.traffic.apply(source, upstreams.region.as_deref(), upstreams.options.depart_at, outcome.result);
+                let result = ctx.calibration.apply(source, upstreams.region.as_deref(), upstreams.options.depart_at, result);
+                outcome.result = ctx.health.track(source, ctx.plausibility.check(upstreams.profile.name.as_str(), boundary, result));
+                outcomes.push(outcome);
+            },
+            Err(_) => println!("compare worker panicked"),
+        }
+    }
+
+    let baseline = match baseline {
+        Some(b) => outcomes.iter().find(|o| o.source == b.as_str() && o.result.0.code < 400),
+        None => outcomes.iter().find(|o| o.result.0.code < 400),
+    };
+
+    let baseline_route = baseline.map(|b| first_route(&b.result.1));
+    let providers: Vec<Value> = outcomes.iter().map(|o| -> Value {
+        if o.result.0.code >= 400 {
+            return json!({
+                "source": o.source,
+                "status": o.result.0.code,
+                "latency_ms": o.latency_ms,
+                "message": o.result.1["message"],
+            }).into();
+        }
+
+        let route = first_route(&o.result.1);
+        let delta = match &baseline_route {
+            Some(base) => route_delta(base, &route),
+            None => Value::Null,
+        };
+
+        json!({
+            "source": o.source,
+            "status": o.result.0.code,
+            "latency_ms": o.latency_ms,
+            "route": route,
+            "delta": delta,
+        }).into()
+    }).collect();
+
+    status::Custom(Status::Ok, json!({
+        "code": "Ok",
+        "baseline": baseline.map(|b| b.source.as_str()),
+        "providers": providers,
+    }))
+}
+
+fn first_route(response: &JsonValue) -> Value {
+    let route = &response["routes"][0];
+    json!({
+        "distance": route["distance"],
+        "duration": route["duration"],
+        "geometry": route["geometry"],
+    }).into()
+}
+
+fn route_delta(base: &Value, route: &Value) -> Value {
+    let delta = |key: &str| match (route[key].as_f64(), base[key].as_f64()) {
+        (Some(r), Some(b)) => Some(r - b),
+        _ => None,
+    };
+
+    let ratio = |key: &str| match (route[key].as_f64(), base[key].as_f64()) {
+        (Some(r), Some(b)) if b > 0.0 => Some(r / b),
+        _ => None,
+    };
+
+    let geometry = match (base["geometry"].as_str(), route["geometry"].as_str()) {
+        (Some(a), Some(b)) => similarity::compare_geometries(a, b, 5),
+        _ => None,
+    };
+
+    json!({
+        "distance": delta("distance"),
+        "duration": delta("duration"),
+        "distance_ratio": ratio("distance"),
+        "duration_ratio": ratio("duration"),
+        "geometry": geometry,
+    }).into()
+}
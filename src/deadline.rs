@@ -0,0 +1,70 @@
+use std::time::{Duration, Instant};
+
+use rocket::Outcome;
+use rocket::request::{self, FromRequest, Request};
+
+pub const TIMEOUT_HEADER: &str = "X-Request-Timeout";
+
+// Budget shared by every upstream call made while serving one request.
+#[derive(Clone, Copy)]
+pub struct Deadline {
+    started: Instant,
+    budget: Duration,
+    per_call: Duration,
+}
+
+impl Deadline {
+    pub fn new(budget_ms: u64, per_call_ms: u64) -> Self {
+        Self {
+            started: Instant::now(),
+            budget: Duration::from_millis(budget_ms),
+            per_call: Duration::from_millis(per_call_ms),
+        }
+    }
+
+    pub fn remaining(&self) -> Duration {
+        match self.budget.checked_sub(self.started.elapsed()) {
+            Some(r) => r,
+            None => Duration::from_millis(0),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == Duration::from_millis(0)
+    }
+
+    // timeout for the next upstream call, capped by limit_timeout
+    pub fn next_timeout(&self) -> Option<Duration> {
+        let remaining = self.remaining();
+        if remaining == Duration::from_millis(0) {
+            return None;
+        }
+
+        if remaining < self.per_call {
+            return Some(remaining);
+        }
+        Some(self.per_call)
+    }
+}
+
+// Value of the X-Request-Timeout header in ms, if present and valid.
+pub struct TimeoutHeader(pub Option<u64>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for TimeoutHeader {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let timeout = match request.headers().get_one(TIMEOUT_HEADER) {
+            Some(t) => t.trim().parse::<u64>().ok(),
+            None => None,
+        };
+        Outcome::Success(TimeoutHeader(timeout))
+    }
+}
+
+pub fn resolve_budget(default_ms: u64, query: Option<u64>, header: Option<u64>) -> u64 {
+    match query.or(header) {
+        Some(t) if t > 0 => t,
+        _ => default_ms,
+    }
+}
@@ -0,0 +1,73 @@
+use geo::LineString;
+use polyline::encode_coordinates;
+
+use rocket::config::Config;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket_contrib::json::JsonValue;
+
+use crate::utils;
+
+// Straight-line route scaled by a circuity factor and driven at an average
+// speed, the last resort when every routing provider fails.
+#[derive(Debug, Clone, Copy)]
+pub struct Estimate {
+    pub circuity: f64,
+    // km/h
+    pub speed: f64,
+}
+
+impl Estimate {
+    // estimate = { car = { circuity = 1.4, speed = 30.0 }, hanoi_motorbike = { ... } },
+    // "<region>_<profile>" wins over "<profile>"
+    pub fn from_config(config: &Config, region: Option<&str>, profile: &str) -> Self {
+        let mut estimate = Self {
+            circuity: 1.4,
+            speed: 30.0,
+        };
+
+        let table = match config.get_table("estimate") {
+            Ok(t) => t,
+            _ => return estimate,
+        };
+
+        let mut keys = vec![profile.to_string()];
+        if let Some(r) = region {
+            keys.insert(0, format!("{}_{}", r, profile));
+        }
+
+        if let Some(entry) = keys.iter().find_map(|k| table.get(k.as_str()).and_then(|e| e.as_table())) {
+            if let Some(c) = entry.get("circuity").and_then(|c| c.as_float().or_else(|| c.as_integer().map(|i| i as f64))) {
+                estimate.circuity = c;
+            }
+
+            if let Some(s) = entry.get("speed").and_then(|s| s.as_float().or_else(|| s.as_integer().map(|i| i as f64))) {
+                estimate.speed = s;
+            }
+        }
+        estimate
+    }
+
+    pub fn route(&self, boundary: &str) -> status::Custom<JsonValue> {
+        if self.speed <= 0.0 {
+            return utils::format_response("estimate speed must be positive", Status::BadRequest);
+        }
+
+        let waypoints = utils::get_waypoints(boundary);
+        let line: LineString<f64> = waypoints.iter().map(|p| (p.x(), p.y())).collect::<Vec<(f64, f64)>>().into();
+        let geometry = match encode_coordinates(line, 5) {
+            Ok(g) => g,
+            Err(err) => {
+                println!("encode estimate geometry fail: {}", err);
+                return utils::format_response("encode geometry fail", Status::BadRequest);
+            }
+        };
+
+        let distance = utils::calc_vincenty_distance(boundary) * self.circuity;
+        let duration = distance / (self.speed / 3.6);
+
+        let mut response = utils::format_response_third_party(geometry.as_str(), distance.round() as u64, duration.round() as u64, "estimate");
+        response["estimated"] = json!(true).into();
+        status::Custom(Status::Ok, response)
+    }
+}
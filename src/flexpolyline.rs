@@ -0,0 +1,113 @@
+// Decoder for HERE's flexible polyline: a version and a header with the
+// precisions, then zigzag varint deltas of lat, lng and an optional third
+// dimension, 6 bits per character.
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+struct Decoder<'a> {
+    chars: std::str::Bytes<'a>,
+}
+
+impl<'a> Decoder<'a> {
+    fn unsigned(&mut self) -> Result<Option<u64>, String> {
+        let mut result: u64 = 0;
+        let mut shift = 0;
+        let mut started = false;
+        for c in &mut self.chars {
+            let value = match ALPHABET.iter().position(|a| *a == c) {
+                Some(v) => v as u64,
+                None => return Err(format!("invalid flexible polyline character {}", c as char)),
+            };
+
+            started = true;
+            result |= (value & 0x1f) << shift;
+            if value & 0x20 == 0 {
+                return Ok(Some(result));
+            }
+
+            shift += 5;
+            if shift > 60 {
+                return Err(String::from("flexible polyline value overflows"));
+            }
+        }
+
+        if started {
+            return Err(String::from("truncated flexible polyline"));
+        }
+        Ok(None)
+    }
+
+    fn signed(&mut self) -> Result<Option<i64>, String> {
+        Ok(self.unsigned()?.map(|v| {
+            if v & 1 == 1 { !((v >> 1) as i64) } else { (v >> 1) as i64 }
+        }))
+    }
+}
+
+// (lat, lng) pairs, a third dimension is read and dropped
+pub fn decode(encoded: &str) -> Result<Vec<(f64, f64)>, String> {
+    let mut decoder = Decoder { chars: encoded.bytes() };
+    match decoder.unsigned()? {
+        Some(1) => {},
+        Some(v) => return Err(format!("unsupported flexible polyline version {}", v)),
+        None => return Err(String::from("empty flexible polyline")),
+    }
+
+    let header = match decoder.unsigned()? {
+        Some(h) => h,
+        None => return Err(String::from("missing flexible polyline header")),
+    };
+    let factor = 10f64.powi((header & 0x0f) as i32);
+    let third_dimension = (header >> 4) & 0x07 != 0;
+
+    let mut coordinates: Vec<(f64, f64)> = Vec::new();
+    let (mut lat, mut lng) = (0i64, 0i64);
+    while let Some(dlat) = decoder.signed()? {
+        let dlng = match decoder.signed()? {
+            Some(d) => d,
+            None => return Err(String::from("truncated flexible polyline")),
+        };
+
+        if third_dimension && decoder.signed()?.is_none() {
+            return Err(String::from("truncated flexible polyline"));
+        }
+
+        lat += dlat;
+        lng += dlng;
+        coordinates.push((lat as f64 / factor, lng as f64 / factor));
+    }
+    Ok(coordinates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(decoded: &[(f64, f64)], expected: &[(f64, f64)]) {
+        assert_eq!(decoded.len(), expected.len());
+        for (d, e) in decoded.iter().zip(expected) {
+            assert!((d.0 - e.0).abs() < 1e-9 && (d.1 - e.1).abs() < 1e-9, "{:?} != {:?}", d, e);
+        }
+    }
+
+    // reference vectors from the flexible polyline specification
+    const POINTS: &[(f64, f64)] = &[(50.10228, 8.69821), (50.10201, 8.69567), (50.10063, 8.69150), (50.09878, 8.68752)];
+
+    #[test]
+    fn decodes_the_reference_polyline() {
+        assert_close(&decode("BFoz5xJ67i1B1B7PzIhaxL7Y").unwrap(), POINTS);
+    }
+
+    #[test]
+    fn drops_the_third_dimension() {
+        // altitudes 10, 20, 30 and 40 at precision 0
+        assert_close(&decode("BlBoz5xJ67i1BU1B7PUzIhaUxL7YU").unwrap(), POINTS);
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert!(decode("").is_err());
+        assert!(decode("CFoz5xJ67i1B").is_err());
+        assert!(decode("BFoz5xJ67i1B1B7PzIhaxL7").is_err());
+        assert!(decode("BFoz5xJ!").is_err());
+    }
+}
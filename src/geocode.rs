@@ -0,0 +1,223 @@
+use reqwest::blocking::{Client, RequestBuilder};
+
+use rocket::config::Config;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket_contrib::json::JsonValue;
+
+use crate::deadline::Deadline;
+use crate::regions;
+use crate::utils;
+
+pub enum Query<'a> {
+    Address(&'a str),
+    // lat, lng
+    Reverse(f64, f64),
+}
+
+// Geocoder settings read per request like Upstreams, tried in order until
+// one returns a result.
+pub struct Geocoders {
+    pub sources: Vec<String>,
+    vietbando_geocode_url: String,
+    vietbando_reverse_url: String,
+    vietbando_api_key: String,
+    google_geocode_url: String,
+    google_api_key: String,
+    nominatim_url: String,
+    nominatim_user_agent: String,
+}
+
+impl Geocoders {
+    pub fn from_config(config: &Config, sources: Option<&str>) -> Self {
+        let get = |name: &str| match config.get_str(name) {
+            Ok(v) => v.to_string(),
+            _ => String::from(""),
+        };
+
+        let sources = match sources {
+            Some(s) => regions::split_sources(s),
+            None => match config.get_str("geocode_sources") {
+                Ok(s) if !s.is_empty() => regions::split_sources(s),
+                _ => vec![String::from("vietbando"), String::from("google"), String::from("nominatim")],
+            },
+        };
+
+        let nominatim_user_agent = match config.get_str("nominatim_user_agent") {
+            Ok(a) if !a.is_empty() => a.to_string(),
+            _ => String::from("osrm-wrapper"),
+        };
+
+        Self {
+            sources,
+            vietbando_geocode_url: get("vietbando_geocode_url"),
+            vietbando_reverse_url: get("vietbando_reverse_url"),
+            vietbando_api_key: get("vietbando_api_key"),
+            google_geocode_url: get("google_geocode_url"),
+            google_api_key: get("google_api_key"),
+            nominatim_url: get("nominatim_url"),
+            nominatim_user_agent,
+        }
+    }
+
+    fn is_configured(&self, source: &str, query: &Query) -> bool {
+        match (source, query) {
+            ("vietbando", Query::Address(_)) => !self.vietbando_geocode_url.is_empty() && !self.vietbando_api_key.is_empty(),
+            ("vietbando", Query::Reverse(..)) => !self.vietbando_reverse_url.is_empty() && !self.vietbando_api_key.is_empty(),
+            ("google", _) => !self.google_geocode_url.is_empty() && !self.google_api_key.is_empty(),
+            ("nominatim", _) => !self.nominatim_url.is_empty(),
+            _ => false,
+        }
+    }
+
+    // The first provider with a result, failures move on to the next one.
+    pub fn geocode(&self, query: &Query, client: &Client, deadline: &Deadline) -> status::Custom<JsonValue> {
+        let mut result = utils::format_response("no geocoder configured", Status::BadRequest);
+        for source in self.sources.iter() {
+            if !["vietbando", "google", "nominatim"].contains(&source.as_str()) {
+                return utils::format_response(format!("unknown geocoder {}", source).as_str(), Status::BadRequest);
+            }
+
+            if !self.is_configured(source, query) {
+                continue;
+            }
+
+            let timeout = match deadline.next_timeout() {
+                Some(t) => t,
+                None => return utils::deadline_exceeded(),
+            };
+
+            let req = match source.as_str() {
+                "vietbando" => self.vietbando_request(query, client),
+                "google" => self.google_request(query, client),
+                _ => self.nominatim_request(query, client),
+            };
+
+            let body = match req.timeout(timeout).send().and_then(|r| r.error_for_status()).and_then(|r| r.json::<serde_json::Value>()) {
+                Ok(b) => b,
+                Err(err) => {
+                    println!("call {} geocoder fail: {}", source, utils::without_url(&err));
+                    result = utils::format_response("request fail", Status::BadRequest);
+                    continue;
+                }
+            };
+
+            let addresses = match source.as_str() {
+                "vietbando" => parse_vietbando(&body),
+                "google" => parse_google(&body),
+                _ => parse_nominatim(&body),
+            };
+
+            if addresses.is_empty() {
+                result = utils::format_response("no result", Status::NotFound);
+                continue;
+            }
+
+            return status::Custom(Status::Ok, json!({
+                "code": "Ok",
+                "results": addresses,
+                "source": source,
+            }));
+        }
+        result
+    }
+
+    fn vietbando_request(&self, query: &Query, client: &Client) -> RequestBuilder {
+        let (url, body): (&str, serde_json::Value) = match query {
+            Query::Address(a) => (self.vietbando_geocode_url.as_str(), json!({
+                "Keyword": a,
+                "Page": 1,
+                "PageSize": 5,
+                "IsOrder": true,
+            }).into()),
+            Query::Reverse(lat, lng) => (self.vietbando_reverse_url.as_str(), json!({
+                "Latitude": lat,
+                "Longitude": lng,
+            }).into()),
+        };
+
+        client.post(url)
+            .header("RegisterKey", self.vietbando_api_key.as_str())
+            .json(&body)
+    }
+
+    fn google_request(&self, query: &Query, client: &Client) -> RequestBuilder {
+        let req = client.get(self.google_geocode_url.as_str());
+        match query {
+            Query::Address(a) => req.query(&[("address", *a), ("key", self.google_api_key.as_str())]),
+            Query::Reverse(lat, lng) => req.query(&[("latlng", format!("{},{}", lat, lng).as_str()), ("key", self.google_api_key.as_str())]),
+        }
+    }
+
+    fn nominatim_request(&self, query: &Query, client: &Client) -> RequestBuilder {
+        let req = match query {
+            Query::Address(a) => client.get(format!("{}/search", self.nominatim_url).as_str())
+                .query(&[("q", *a), ("limit", "5")]),
+            Query::Reverse(lat, lng) => client.get(format!("{}/reverse", self.nominatim_url).as_str())
+                .query(&[("lat", lat.to_string()), ("lon", lng.to_string())]),
+        };
+
+        // the usage policy asks for an identifying user agent
+        req.query(&[("format", "jsonv2")])
+            .header("User-Agent", self.nominatim_user_agent.as_str())
+    }
+}
+
+// normalized address, lat and lng in degrees
+pub fn address(formatted_address: &str, name: Option<&str>, lat: Option<f64>, lng: Option<f64>, place_id: Option<String>) -> Option<serde_json::Value> {
+    Some(json!({
+        "formatted_address": formatted_address,
+        "name": name,
+        "lat": lat?,
+        "lng": lng?,
+        "place_id": place_id,
+    }).into())
+}
+
+fn parse_vietbando(body: &serde_json::Value) -> Vec<serde_json::Value> {
+    if body["IsSuccess"].as_bool() == Some(false) {
+        return Vec::new();
+    }
+
+    // search answers a list, reverse a single value
+    let items: Vec<&serde_json::Value> = match body["List"].as_array().or_else(|| body["Value"]["List"].as_array()) {
+        Some(l) => l.iter().collect(),
+        None if body["Value"].is_object() => vec![&body["Value"]],
+        None => Vec::new(),
+    };
+
+    items.iter().filter_map(|i| address(
+        i["Address"].as_str().unwrap_or(""),
+        i["Name"].as_str(),
+        i["Latitude"].as_f64(),
+        i["Longitude"].as_f64(),
+        i["Id"].as_str().map(|id| id.to_string()),
+    )).collect()
+}
+
+fn parse_google(body: &serde_json::Value) -> Vec<serde_json::Value> {
+    body["results"].as_array().map(|r| r.as_slice()).unwrap_or(&[]).iter().filter_map(|r| address(
+        r["formatted_address"].as_str().unwrap_or(""),
+        None,
+        r["geometry"]["location"]["lat"].as_f64(),
+        r["geometry"]["location"]["lng"].as_f64(),
+        r["place_id"].as_str().map(|id| id.to_string()),
+    )).collect()
+}
+
+fn parse_nominatim(body: &serde_json::Value) -> Vec<serde_json::Value> {
+    // search answers an array, reverse a single place
+    let places: Vec<&serde_json::Value> = match body.as_array() {
+        Some(p) => p.iter().collect(),
+        None if body["error"].is_null() && body.is_object() => vec![body],
+        None => Vec::new(),
+    };
+
+    places.iter().filter_map(|p| address(
+        p["display_name"].as_str().unwrap_or(""),
+        p["name"].as_str().filter(|n| !n.is_empty()),
+        p["lat"].as_str().and_then(|l| l.parse::<f64>().ok()),
+        p["lon"].as_str().and_then(|l| l.parse::<f64>().ok()),
+        p["place_id"].as_u64().map(|id| id.to_string()),
+    )).collect()
+}
@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use rocket::response::status;
+use rocket_contrib::json::JsonValue;
+
+use crate::utils;
+
+// Counts consecutive failures per provider, a provider is unhealthy once
+// it reaches the threshold and healthy again after its next success.
+pub struct ProviderHealth {
+    failures: Mutex<HashMap<String, u32>>,
+    threshold: u32,
+}
+
+impl ProviderHealth {
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            failures: Mutex::new(HashMap::new()),
+            threshold,
+        }
+    }
+
+    pub fn record(&self, provider: &str, ok: bool) {
+        let mut failures = match self.failures.lock() {
+            Ok(f) => f,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if ok {
+            failures.remove(provider);
+            return;
+        }
+        *failures.entry(provider.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn track(&self, provider: &str, result: status::Custom<JsonValue>) -> status::Custom<JsonValue> {
+        if result.0.code < 400 || utils::is_provider_failure(&result) {
+            self.record(provider, result.0.code < 400);
+        }
+        result
+    }
+
+    pub fn is_healthy(&self, provider: &str) -> bool {
+        let failures = match self.failures.lock() {
+            Ok(f) => f,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        match failures.get(provider) {
+            Some(count) => *count < self.threshold,
+            None => true,
+        }
+    }
+}
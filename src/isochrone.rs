@@ -0,0 +1,446 @@
+use std::time::Instant;
+
+use geo::{Coordinate, Line, LineString, Point};
+use geo::algorithm::area::Area;
+use geo::algorithm::convexhull::ConvexHull;
+use geo::algorithm::euclidean_distance::EuclideanDistance;
+use geo::algorithm::intersects::Intersects;
+use reqwest::blocking::Client;
+
+use rocket::config::Config;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket_contrib::json::JsonValue;
+
+use crate::deadline::Deadline;
+use crate::pool::OsrmPool;
+use crate::profiles::Profiles;
+use crate::regions::{self, Regions};
+use crate::{service, utils};
+
+// km per degree of latitude, and of longitude at the equator
+const KM_PER_LAT: f64 = 110.574;
+const KM_PER_LNG: f64 = 111.320;
+
+// Reachability polygons, from Valhalla's isochrone service when it serves
+// the profile, otherwise by timing a grid of samples with the OSRM table
+// service and wrapping the reachable ones in a concave hull.
+pub struct Isochrones {
+    pub sources: Vec<String>,
+    valhalla_url: String,
+    valhalla_costing: Option<String>,
+    osrm_groups: Vec<String>,
+    max_minutes: u64,
+    // samples per side of the grid
+    grid_size: usize,
+    // km/h, sizes the grid for the largest contour
+    max_speed: f64,
+    // coordinates per table request, the origin included
+    table_size: usize,
+    // edge length over dig distance above which the hull is dug in
+    concavity: f64,
+}
+
+impl Isochrones {
+    pub fn from_config(config: &Config, regions: &Regions, profiles: &Profiles, profile: &str, origin: Point<f64>, sources: Option<&str>) -> Result<Self, status::Custom<JsonValue>> {
+        let profile = match profiles.get(Some(profile)) {
+            Ok(p) => p,
+            Err(err) => return Err(utils::format_response(err.as_str(), Status::BadRequest)),
+        };
+
+        let sources = match sources {
+            Some(s) => regions::split_sources(s),
+            None => match config.get_str("isochrone_sources") {
+                Ok(s) if !s.is_empty() => regions::split_sources(s),
+                _ => vec![String::from("valhalla"), String::from("osrm")],
+            },
+        };
+
+        let (_, region_osrm, _) = regions.route_for(&[origin]);
+
+        let max_minutes = match config.get_int("isochrone_max_minutes") {
+            Ok(m) if m > 0 => m as u64,
+            _ => 60,
+        };
+
+        // odd so the origin sits on the grid
+        let grid_size = match config.get_int("isochrone_grid_size") {
+            Ok(s) if s >= 3 => (s as usize) | 1,
+            _ => 21,
+        };
+
+        let max_speed = match config.get_float("isochrone_max_speed") {
+            Ok(s) if s > 0.0 => s,
+            _ => 60.0,
+        };
+
+        let table_size = match config.get_int("isochrone_table_size") {
+            Ok(s) if s >= 2 => s as usize,
+            _ => 100,
+        };
+
+        let concavity = match config.get_float("isochrone_concavity") {
+            Ok(c) if c > 0.0 => c,
+            _ => 2.0,
+        };
+
+        Ok(Self {
+            sources,
+            valhalla_url: match config.get_str("valhalla_url") {
+                Ok(u) => u.to_string(),
+                _ => String::from(""),
+            },
+            valhalla_costing: profile.valhalla_costing.clone(),
+            osrm_groups: service::osrm_groups(profiles, profile, region_osrm),
+            max_minutes,
+            grid_size,
+            max_speed,
+            table_size,
+            concavity,
+        })
+    }
+
+    // "10,20,30" sorted, each within isochrone_max_minutes
+    pub fn parse_minutes(&self, minutes: &str) -> Result<Vec<u64>, String> {
+        let mut contours: Vec<u64> = Vec::new();
+        for m in minutes.split(',').map(|m| m.trim()).filter(|m| !m.is_empty()) {
+            match m.parse::<u64>() {
+                Ok(m) if m > 0 && m <= self.max_minutes => contours.push(m),
+                _ => return Err(format!("minutes must be between 1 and {}", self.max_minutes)),
+            }
+        }
+
+        if contours.is_empty() {
+            return Err(String::from("missing minutes"));
+        }
+
+        contours.sort_unstable();
+        contours.dedup();
+        Ok(contours)
+    }
+
+    // The first source with polygons, failures move on to the next one.
+    pub fn compute(&self, origin: Point<f64>, minutes: &[u64], pool: &OsrmPool, client: &Client, deadline: &Deadline) -> status::Custom<JsonValue> {
+        let mut result = utils::format_response("no isochrone source configured", Status::BadRequest);
+        for source in self.sources.iter() {
+            let features = match source.as_str() {
+                "valhalla" => match &self.valhalla_costing {
+                    Some(costing) if !self.valhalla_url.is_empty() => self.valhalla(origin, costing.as_str(), minutes, client, deadline),
+                    _ => continue,
+                },
+                "osrm" => self.osrm(origin, minutes, pool, client, deadline),
+                _ => return utils::format_response(format!("unknown isochrone source {}", source).as_str(), Status::BadRequest),
+            };
+
+            match features {
+                Ok(f) if !f.is_empty() => return status::Custom(Status::Ok, json!({
+                    "code": "Ok",
+                    "type": "FeatureCollection",
+                    "features": f,
+                    "source": source,
+                })),
+                Ok(_) => result = utils::format_response("no isochrone found", Status::NotFound),
+                Err(e) if e.0 == Status::GatewayTimeout => return e,
+                Err(e) => result = e,
+            }
+        }
+        result
+    }
+
+    fn valhalla(&self, origin: Point<f64>, costing: &str, minutes: &[u64], client: &Client, deadline: &Deadline) -> Result<Vec<serde_json::Value>, status::Custom<JsonValue>> {
+        let timeout = match deadline.next_timeout() {
+            Some(t) => t,
+            None => return Err(utils::deadline_exceeded()),
+        };
+
+        let contours: Vec<serde_json::Value> = minutes.iter().map(|m| json!({"time": m}).into()).collect();
+        let body: serde_json::Value = json!({
+            "locations": [{"lat": origin.y(), "lon": origin.x()}],
+            "costing": costing,
+            "contours": contours,
+            "polygons": true,
+        }).into();
+
+        let res = match client.post(format!("{}/isochrone", self.valhalla_url).as_str()).json(&body).timeout(timeout).send() {
+            Ok(r) => r,
+            Err(err) => {
+                println!("call valhalla isochrone fail: {}", err);
+                return Err(utils::format_response("request fail", Status::BadRequest))
+            }
+        };
+
+        let code = res.status().as_u16();
+        let body = match res.json::<serde_json::Value>() {
+            Ok(b) => b,
+            Err(err) => {
+                println!("parse valhalla isochrone response fail: {}", err);
+                return Err(utils::format_response("parse response fail", Status::BadRequest))
+            }
+        };
+
+        if code >= 400 {
+            let message = body["error"].as_str().unwrap_or("request fail");
+            return Err(utils::format_response(message, Status::BadRequest))
+        }
+
+        let mut features: Vec<serde_json::Value> = body["features"].as_array().map(|f| f.as_slice()).unwrap_or(&[]).iter()
+            .filter(|f| f["geometry"]["type"] == "Polygon")
+            .filter_map(|f| Some(feature(f["properties"]["contour"].as_f64()? as u64, &f["geometry"]["coordinates"])))
+            .collect();
+
+        // valhalla answers the largest contour first
+        features.sort_by_key(|f| f["properties"]["contour"].as_u64());
+        Ok(features)
+    }
+
+    fn osrm(&self, origin: Point<f64>, minutes: &[u64], pool: &OsrmPool, client: &Client, deadline: &Deadline) -> Result<Vec<serde_json::Value>, status::Custom<JsonValue>> {
+        let max_minutes = minutes.iter().max().copied().unwrap_or(0);
+        let grid = Grid::new(origin, self.grid_size, self.max_speed * max_minutes as f64 / 60.0);
+        let samples = grid.samples();
+
+        // seconds to each sample, None when unreachable or off the road network
+        let mut durations: Vec<Option<f64>> = Vec::with_capacity(samples.len());
+        for chunk in samples.chunks(self.table_size - 1) {
+            let mut coordinates = vec![format!("{},{}", origin.x(), origin.y())];
+            coordinates.extend(chunk.iter().map(|c| {
+                let (lng, lat) = grid.lng_lat(c);
+                format!("{},{}", lng, lat)
+            }));
+
+            let path = format!("{}?sources=0&annotations=duration", coordinates.join(";"));
+            let body = self.osrm_table(pool, path.as_str(), client, deadline)?;
+
+            for i in 1..=chunk.len() {
+                // a sample snapped further than a cell lies away from any road
+                let snapped = body["destinations"][i]["distance"].as_f64().map_or(false, |d| d <= grid.spacing * 1000.0);
+                durations.push(body["durations"][0][i].as_f64().filter(|_| snapped));
+            }
+        }
+
+        let center = Coordinate { x: grid.half as f64, y: grid.half as f64 };
+        let mut features: Vec<serde_json::Value> = Vec::new();
+        for m in minutes {
+            let mut reachable: Vec<Coordinate<f64>> = samples.iter().zip(durations.iter())
+                .filter(|(_, d)| d.map_or(false, |d| d <= (m * 60) as f64))
+                .map(|(c, _)| *c)
+                .collect();
+            if !reachable.contains(&center) {
+                reachable.push(center);
+            }
+
+            let ring = concave_hull(&reachable, self.concavity);
+            if ring.is_empty() {
+                println!("isochrone {} min from {},{} has too few samples", m, origin.x(), origin.y());
+                continue;
+            }
+
+            let mut coordinates: Vec<[f64; 2]> = ring.iter().map(|c| {
+                let (lng, lat) = grid.lng_lat(c);
+                [lng, lat]
+            }).collect();
+            coordinates.push(coordinates[0]);
+            features.push(feature(*m, &json!([coordinates]).into()));
+        }
+        Ok(features)
+    }
+
+    fn osrm_table(&self, pool: &OsrmPool, path: &str, client: &Client, deadline: &Deadline) -> Result<serde_json::Value, status::Custom<JsonValue>> {
+        let replicas = match pool.resolve(&self.osrm_groups) {
+            Some(group) => pool.candidates(group),
+            None => Vec::new(),
+        };
+
+        if replicas.is_empty() {
+            return Err(utils::format_response(format!("missing osrm upstream config for {}", self.osrm_groups.join(", ")).as_str(), Status::BadRequest));
+        }
+
+        let mut result = utils::format_response("request fail", Status::BadRequest);
+        for replica in replicas {
+            let timeout = match deadline.next_timeout() {
+                Some(t) => t,
+                None => return Err(utils::deadline_exceeded()),
+            };
+
+            // upstreams point at the route service, http://osrm:5000/route/v1/driving
+            let started = Instant::now();
+            let url = format!("{}/{}", replica.url.replacen("/route/", "/table/", 1), path);
+            let res = match client.get(url.as_str()).timeout(timeout).send() {
+                Ok(r) => r,
+                Err(e) => {
+                    pool.record_failure(&replica);
+                    result = utils::err_response("request fail", Status::BadRequest, e);
+                    continue;
+                }
+            };
+
+            let code = res.status().as_u16();
+            if code >= 500 {
+                println!("osrm {} responded {}", replica.url, code);
+                pool.record_failure(&replica);
+                continue;
+            }
+
+            pool.record_success(&replica, started.elapsed());
+            return match res.json::<serde_json::Value>() {
+                Ok(b) if b["code"] == "Ok" => Ok(b),
+                Ok(b) => Err(utils::format_response(b["message"].as_str().unwrap_or("request fail"), Status::BadRequest)),
+                Err(e) => Err(utils::err_response("parse response fail", Status::BadRequest, e)),
+            };
+        }
+        Err(result)
+    }
+}
+
+// Square grid of samples around the origin, kept in cell units so the hull
+// works on an undistorted plane.
+struct Grid {
+    origin: Point<f64>,
+    size: usize,
+    half: usize,
+    // km between samples
+    spacing: f64,
+    km_per_lng: f64,
+}
+
+impl Grid {
+    fn new(origin: Point<f64>, size: usize, radius: f64) -> Self {
+        let half = size / 2;
+        Self {
+            origin,
+            size,
+            half,
+            spacing: radius / half as f64,
+            km_per_lng: KM_PER_LNG * origin.y().to_radians().cos(),
+        }
+    }
+
+    // cells within the radius, the origin excluded
+    fn samples(&self) -> Vec<Coordinate<f64>> {
+        let half = self.half as i64;
+        let mut cells: Vec<Coordinate<f64>> = Vec::new();
+        for row in 0..self.size as i64 {
+            for col in 0..self.size as i64 {
+                let (dx, dy) = (col - half, row - half);
+                if (dx != 0 || dy != 0) && dx * dx + dy * dy <= half * half {
+                    cells.push(Coordinate { x: col as f64, y: row as f64 });
+                }
+            }
+        }
+        cells
+    }
+
+    fn lng_lat(&self, cell: &Coordinate<f64>) -> (f64, f64) {
+        let dx = (cell.x - self.half as f64) * self.spacing;
+        let dy = (cell.y - self.half as f64) * self.spacing;
+        (self.origin.x() + dx / self.km_per_lng, self.origin.y() + dy / KM_PER_LAT)
+    }
+}
+
+fn feature(minutes: u64, coordinates: &serde_json::Value) -> serde_json::Value {
+    json!({
+        "type": "Feature",
+        "properties": {"contour": minutes},
+        "geometry": {"type": "Polygon", "coordinates": coordinates},
+    }).into()
+}
+
+// Concave hull by digging into the convex hull (Park and Oh): an edge is
+// replaced by two through its nearest inner point while the edge is more
+// than concavity times longer than the way in. Returns the open ring, empty
+// for fewer than three non collinear points.
+fn concave_hull(points: &[Coordinate<f64>], concavity: f64) -> Vec<Coordinate<f64>> {
+    if points.len() < 3 {
+        return Vec::new();
+    }
+
+    // the hull keeps collinear points, so a line comes back as a flat ring
+    let hull = LineString::from(points.to_vec()).convex_hull();
+    if hull.unsigned_area() == 0.0 {
+        return Vec::new();
+    }
+
+    let mut ring: Vec<Coordinate<f64>> = hull.exterior().0.clone();
+    ring.pop();
+    if ring.len() < 3 {
+        return Vec::new();
+    }
+
+    let mut inner: Vec<Coordinate<f64>> = points.iter().filter(|p| !ring.contains(p)).copied().collect();
+    let mut i = 0;
+    while i < ring.len() {
+        match dig(&ring, i, &inner, concavity) {
+            // edge i now ends at the new point and is tried again
+            Some(k) => ring.insert(i + 1, inner.remove(k)),
+            None => i += 1,
+        }
+    }
+    ring
+}
+
+// index in inner of the point to dig edge i of the ring towards
+fn dig(ring: &[Coordinate<f64>], i: usize, inner: &[Coordinate<f64>], concavity: f64) -> Option<usize> {
+    let n = ring.len();
+    let edge = |j: usize| Line::new(ring[j], ring[(j + 1) % n]);
+    let (a, b) = (Point(ring[i]), Point(ring[(i + 1) % n]));
+
+    // the nearest point among those closer to this edge than to any other
+    let (k, _) = inner.iter().enumerate()
+        .map(|(k, p)| (k, Point(*p).euclidean_distance(&edge(i))))
+        .filter(|(k, d)| (0..n).all(|j| j == i || Point(inner[*k]).euclidean_distance(&edge(j)) >= *d))
+        .min_by(|x, y| x.1.partial_cmp(&y.1).unwrap_or(std::cmp::Ordering::Equal))?;
+
+    let p = Point(inner[k]);
+    let way_in = p.euclidean_distance(&a).min(p.euclidean_distance(&b));
+    if way_in <= 0.0 || a.euclidean_distance(&b) / way_in <= concavity {
+        return None;
+    }
+
+    // the new edges may only touch the neighbours they share a vertex with
+    let (in_edge, out_edge) = (Line::new(a.0, p.0), Line::new(p.0, b.0));
+    let crosses = (0..n)
+        .filter(|j| *j != i && *j != (i + n - 1) % n && *j != (i + 1) % n)
+        .any(|j| in_edge.intersects(&edge(j)) || out_edge.intersects(&edge(j)));
+    if crosses {
+        return None;
+    }
+    Some(k)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn c(x: f64, y: f64) -> Coordinate<f64> {
+        Coordinate { x, y }
+    }
+
+    // the corners of a 10 x 10 square and a point 2 below the middle of its top
+    fn notched() -> Vec<Coordinate<f64>> {
+        vec![c(0.0, 0.0), c(10.0, 0.0), c(10.0, 10.0), c(0.0, 10.0), c(5.0, 8.0)]
+    }
+
+    #[test]
+    fn digs_a_notch_into_a_long_edge() {
+        let ring = concave_hull(&notched(), 1.5);
+        assert_eq!(ring.len(), 5);
+
+        let at = ring.iter().position(|p| *p == c(5.0, 8.0)).unwrap();
+        let mut neighbours = vec![ring[(at + 4) % 5], ring[(at + 1) % 5]];
+        neighbours.sort_by(|a, b| a.x.partial_cmp(&b.x).unwrap());
+        assert_eq!(neighbours, vec![c(0.0, 10.0), c(10.0, 10.0)]);
+    }
+
+    #[test]
+    fn keeps_the_convex_hull_below_the_concavity() {
+        // the top edge is 10 long and the way in about 5.4
+        let ring = concave_hull(&notched(), 2.0);
+        assert_eq!(ring.len(), 4);
+        assert!(!ring.contains(&c(5.0, 8.0)));
+    }
+
+    #[test]
+    fn needs_three_points_off_a_line() {
+        assert!(concave_hull(&[c(0.0, 0.0), c(1.0, 1.0)], 1.5).is_empty());
+        assert!(concave_hull(&[c(0.0, 0.0), c(1.0, 1.0), c(2.0, 2.0)], 1.5).is_empty());
+    }
+}
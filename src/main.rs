@@ -9,9 +9,10 @@ use rocket::State;
 use rocket::config::{Config};
 use rocket::fairing::AdHoc;
 use rocket::http::Status;
+use rocket::request::LenientForm;
 use rocket::response::status;
 
-use rocket_contrib::json::JsonValue;
+use rocket_contrib::json::{Json, JsonValue};
 
 use reqwest::blocking::Client;
 
@@ -20,187 +21,294 @@ extern crate serde_json;
 
 use std::time::Duration;
 
+use calibration::Calibration;
+use geocode::{Geocoders, Query};
+use deadline::{Deadline, TimeoutHeader};
+use health::ProviderHealth;
+use options::{RouteOptions, RouteOptionsQuery};
+use offline::OfflineRouter;
+use places::{Places, Sessions};
+use isochrone::Isochrones;
+use plausibility::Plausibility;
+use policy::Policy;
+use pool::OsrmPool;
+use profiles::Profiles;
+use regions::Regions;
+use service::Upstreams;
+use shadow::Shadow;
+use templated::HttpProviders;
+use state::{AppState, LocalConfig};
+use traffic::Traffic;
+
+mod calibration;
+mod compare;
+mod deadline;
+mod estimate;
+mod flexpolyline;
+mod geocode;
+mod health;
+mod isochrone;
+mod offline;
+mod places;
+mod options;
+mod plausibility;
+mod policy;
+mod pool;
+mod profiles;
+mod regions;
 mod model;
 mod utils;
 mod service;
+mod shadow;
+mod similarity;
+mod state;
+mod templated;
+mod traffic;
 
 
-struct LocalConfig(Config);
+fn new_deadline(config: &Config, limit_timeout: u64, timeout: Option<u64>, timeout_header: Option<u64>) -> Deadline {
+    let request_timeout: u64 = match config.get_int("request_timeout") {
+        Ok(t) => t as u64,
+        _ => 1000
+    };
 
-#[get("/route/<profile>/<boundary>", format = "text/html")]
-fn get_route(config: State<LocalConfig>, profile: String, boundary: String) -> status::Custom<JsonValue> {
-    let config = &config.0;
+    Deadline::new(deadline::resolve_budget(request_timeout, timeout, timeout_header), limit_timeout)
+}
 
-    let limit_timeout: u64 = match config.get_int("limit_timeout") {
+fn get_limit_timeout(config: &Config) -> u64 {
+    match config.get_int("limit_timeout") {
         Ok(t) => t as u64,
         _ => 100
+    }
+}
+
+fn new_client(limit_timeout: u64) -> Client {
+    Client::builder()
+        .timeout(Duration::from_millis(limit_timeout))
+        .build()
+        .unwrap()
+}
+
+#[get("/route/<source>/<boundary>?<alternatives>&<profile>&<timeout>&<options..>", format = "text/html")]
+fn get_route(state: AppState, timeout_header: TimeoutHeader, source: String, boundary: String, alternatives: Option<bool>, profile: Option<String>, timeout: Option<u64>, options: LenientForm<RouteOptionsQuery>) -> status::Custom<JsonValue> {
+    let config = &state.config.0;
+
+    // /route has always asked for alternatives
+    let options = match RouteOptions::parse(&options, alternatives.unwrap_or(true)) {
+        Ok(o) => o,
+        Err(err) => return utils::format_response(err.as_str(), Status::BadRequest),
     };
 
-    let osrm_url_default = match config.get_str("osrm_url_default") {
-        Ok(u) => format!("{}/{}?alternatives=true", u.to_string().as_str(), boundary),
-        _ => return utils::format_response("missing osrm_url_default config", Status::BadRequest)
+    let limit_timeout = get_limit_timeout(config);
+    let deadline = new_deadline(config, limit_timeout, timeout, timeout_header.0);
+
+    let upstreams = match Upstreams::from_config(config, &state.regions, &state.profiles, &state.http_providers, profile.as_deref(), boundary.as_str(), options) {
+        Ok(u) => u,
+        Err(e) => return e,
     };
 
-    let osrm_url = match config.get_str("osrm_url") {
-        Ok(u) => format!("{}/{}?alternatives=true", u, boundary),
-        _ => String::from("")
+    let client = new_client(limit_timeout);
+    let ctx = state.context(&client, &deadline);
+
+    if !upstreams.knows(source.as_str()) {
+        return utils::format_response("missing or wrong source param", Status::BadRequest);
+    }
+
+    service::call_source(source.as_str(), &upstreams, boundary.as_str(), &ctx)
+}
+
+#[get("/driving/<boundary>?<alternatives>&<sources>&<profile>&<timeout>&<options..>", format = "text/html")]
+fn get_driving(state: AppState, timeout_header: TimeoutHeader, boundary: String, alternatives: bool, sources: Option<String>, profile: Option<String>, timeout: Option<u64>, options: LenientForm<RouteOptionsQuery>) -> status::Custom<JsonValue> {
+    let config = &state.config.0;
+
+    let options = match RouteOptions::parse(&options, alternatives) {
+        Ok(o) => o,
+        Err(err) => return utils::format_response(err.as_str(), Status::BadRequest),
     };
 
-    let vietbando_api_key: String = match config.get_str("vietbando_api_key") {
-        Ok(v) => v.to_string(),
-        _ => String::from("")
+    let limit_timeout = get_limit_timeout(config);
+    let deadline = new_deadline(config, limit_timeout, timeout, timeout_header.0);
+
+    let upstreams = match Upstreams::from_config(config, &state.regions, &state.profiles, &state.http_providers, profile.as_deref(), boundary.as_str(), options) {
+        Ok(u) => u,
+        Err(e) => return e,
     };
 
-    let vietbando_url: String = match config.get_str("vietbando_url") {
-        Ok(v) => v.to_string(),
-        _ => String::from("")
+    let client = new_client(limit_timeout);
+    let ctx = state.context(&client, &deadline);
+
+    let chain = match sources {
+        Some(s) => regions::split_sources(s.as_str()),
+        None => upstreams.region_sources.clone(),
     };
 
-    let client = Client::builder()
-        .timeout(Duration::from_millis(limit_timeout))
-        .build()
-        .unwrap();
-    match profile.as_str() {
-        "smart" => {
-            service::call_smart(
-                osrm_url.as_str(), 
-                osrm_url_default.as_str(), 
-                vietbando_url.as_str(), 
-                vietbando_api_key.as_str(),
-                boundary.as_str(), &client)
-        },
-        "osrm" => {
-            service::call_osrm(osrm_url.as_str(), osrm_url_default.as_str(), &client)
-        },
-        "vietbando" => {
-            service::call_vietbando(vietbando_url.as_str(), vietbando_api_key.as_str(), boundary.as_str(), &client)
-        },
-        "google" => {
-            let google_url = match config.get_str("google_url") {
-                Ok(url) => url.to_string(),
-                _ => String::from("")
-            };
-        
-            let google_api_key = match config.get_str("google_api_key") {
-                Ok(key) => key.to_string(),
-                _ => String::from("")
-            };
+    for source in chain.iter() {
+        if deadline.is_expired() {
+            return utils::deadline_exceeded();
+        }
 
-            service::call_google(google_url.as_str(), google_api_key.as_str(), boundary.as_str(), &client)
-        },
-        _ => {
-            utils::format_response("missing or wrong profile param", Status::BadRequest)
+        let result = service::call_source(source, &upstreams, boundary.as_str(), &ctx);
+        if result.0.code >= 400 {
+            continue;
         }
+        return result;
     }
+
+    let default_source = match config.get_str("default_source") {
+        Ok(d) if upstreams.knows(d) => d,
+        _ => "osrm",
+    };
+
+    service::call_source(default_source, &upstreams, boundary.as_str(), &ctx)
 }
 
-#[get("/driving/<boundary>?<alternatives>&<sources>", format = "text/html")]
-fn get_driving(config: State<LocalConfig>, boundary: String, alternatives: bool, sources: Option<String>) -> status::Custom<JsonValue> {
-    let config = &config.0;
-    let osrm_url_default = match config.get_str("osrm_url_default") {
-        Ok(u) => format!("{}/{}?alternatives={}", u.to_string().as_str(), boundary, alternatives),
-        _ => return utils::format_response("missing osrm_url_default config", Status::BadRequest)
+#[get("/compare/<boundary>?<baseline>&<profile>&<timeout>&<options..>", format = "text/html")]
+fn get_compare(state: AppState, timeout_header: TimeoutHeader, boundary: String, baseline: Option<String>, profile: Option<String>, timeout: Option<u64>, options: LenientForm<RouteOptionsQuery>) -> status::Custom<JsonValue> {
+    let config = &state.config.0;
+
+    let options = match RouteOptions::parse(&options, false) {
+        Ok(o) => o,
+        Err(err) => return utils::format_response(err.as_str(), Status::BadRequest),
     };
 
-    let osrm_url = match config.get_str("osrm_url") {
-        Ok(u) => format!("{}/{}?alternatives={}", u, boundary, alternatives),
-        _ => String::from("")
+    let limit_timeout = get_limit_timeout(config);
+    let deadline = new_deadline(config, limit_timeout, timeout, timeout_header.0);
+
+    let upstreams = match Upstreams::from_config(config, &state.regions, &state.profiles, &state.http_providers, profile.as_deref(), boundary.as_str(), options) {
+        Ok(u) => u,
+        Err(e) => return e,
     };
 
-    let limit_timeout: u64 = match config.get_int("limit_timeout") {
-        Ok(t) => t as u64,
-        _ => 100
+    let client = new_client(limit_timeout);
+    let ctx = state.context(&client, &deadline);
+
+    compare::compare(&upstreams, boundary.as_str(), baseline, &ctx)
+}
+
+#[get("/geocode?<address>&<sources>&<timeout>")]
+fn get_geocode(config: State<LocalConfig>, timeout_header: TimeoutHeader, address: String, sources: Option<String>, timeout: Option<u64>) -> status::Custom<JsonValue> {
+    let config = &config.0;
+    if address.trim().is_empty() {
+        return utils::format_response("missing address", Status::BadRequest);
+    }
+
+    let limit_timeout = get_limit_timeout(config);
+    let deadline = new_deadline(config, limit_timeout, timeout, timeout_header.0);
+    let geocoders = Geocoders::from_config(config, sources.as_deref());
+
+    geocoders.geocode(&Query::Address(address.as_str()), &new_client(limit_timeout), &deadline)
+}
+
+#[get("/reverse/<coordinate>?<sources>&<timeout>")]
+fn get_reverse(config: State<LocalConfig>, timeout_header: TimeoutHeader, coordinate: String, sources: Option<String>, timeout: Option<u64>) -> status::Custom<JsonValue> {
+    let config = &config.0;
+    let point = match utils::parse_coordinate(coordinate.as_str()) {
+        Some(p) => p,
+        None => return utils::format_response("invalid coordinate, expected lng,lat", Status::BadRequest),
     };
 
-    let vietbando_api_key: String = match config.get_str("vietbando_api_key") {
-        Ok(v) => v.to_string(),
-        _ => String::from("")
+    let limit_timeout = get_limit_timeout(config);
+    let deadline = new_deadline(config, limit_timeout, timeout, timeout_header.0);
+    let geocoders = Geocoders::from_config(config, sources.as_deref());
+
+    geocoders.geocode(&Query::Reverse(point.y(), point.x()), &new_client(limit_timeout), &deadline)
+}
+
+#[get("/autocomplete?<q>&<near>&<session>&<sources>&<timeout>")]
+fn get_autocomplete(config: State<LocalConfig>, sessions: State<Sessions>, timeout_header: TimeoutHeader, q: String, near: Option<String>, session: Option<String>, sources: Option<String>, timeout: Option<u64>) -> status::Custom<JsonValue> {
+    let config = &config.0;
+    if q.trim().is_empty() {
+        return utils::format_response("missing q", Status::BadRequest);
+    }
+
+    let near = match near {
+        Some(n) => match utils::parse_coordinate(n.as_str()) {
+            Some(p) => Some(p),
+            None => return utils::format_response("invalid near, expected lng,lat", Status::BadRequest),
+        },
+        None => None,
     };
 
-    let vietbando_url: String = match config.get_str("vietbando_url") {
-        Ok(v) => v.to_string(),
-        _ => String::from("")
+    let limit_timeout = get_limit_timeout(config);
+    let deadline = new_deadline(config, limit_timeout, timeout, timeout_header.0);
+    let places = Places::from_config(config, sources.as_deref());
+    let session = sessions.open(session.as_deref());
+
+    places.autocomplete(q.as_str(), near, session.as_str(), &new_client(limit_timeout), &deadline)
+}
+
+#[get("/place/<source>/<place_id>?<session>&<timeout>")]
+fn get_place(config: State<LocalConfig>, sessions: State<Sessions>, timeout_header: TimeoutHeader, source: String, place_id: String, session: Option<String>, timeout: Option<u64>) -> status::Custom<JsonValue> {
+    let config = &config.0;
+
+    let limit_timeout = get_limit_timeout(config);
+    let deadline = new_deadline(config, limit_timeout, timeout, timeout_header.0);
+    let places = Places::from_config(config, None);
+
+    // an expired session is billed as a plain details lookup, a failed one
+    // stays open so the client can retry within it
+    let session = session.and_then(|s| sessions.live(s.as_str()));
+    let result = places.details(source.as_str(), place_id.as_str(), session.as_deref(), &new_client(limit_timeout), &deadline);
+    if let Some(s) = &session {
+        if result.0.code < 400 {
+            sessions.close(s.as_str());
+        }
+    }
+    result
+}
+
+#[get("/isochrone/<profile>/<coordinate>?<minutes>&<sources>&<timeout>")]
+fn get_isochrone(state: AppState, timeout_header: TimeoutHeader, profile: String, coordinate: String, minutes: Option<String>, sources: Option<String>, timeout: Option<u64>) -> status::Custom<JsonValue> {
+    let config = &state.config.0;
+    let origin = match utils::parse_coordinate(coordinate.as_str()) {
+        Some(p) => p,
+        None => return utils::format_response("invalid coordinate, expected lng,lat", Status::BadRequest),
     };
 
-    let google_url = match config.get_str("google_url") {
-        Ok(url) => url.to_string(),
-        _ => String::from("")
+    let isochrones = match Isochrones::from_config(config, &state.regions, &state.profiles, profile.as_str(), origin, sources.as_deref()) {
+        Ok(i) => i,
+        Err(e) => return e,
     };
 
-    let google_api_key = match config.get_str("google_api_key") {
-        Ok(key) => key.to_string(),
-        _ => String::from("")
+    let minutes = match isochrones.parse_minutes(minutes.as_deref().unwrap_or("")) {
+        Ok(m) => m,
+        Err(err) => return utils::format_response(err.as_str(), Status::BadRequest),
     };
 
-    let client = Client::builder()
-        .timeout(Duration::from_millis(limit_timeout))
-        .build()
-        .unwrap();
-    
-    if let Some(s) = sources {
-        for source in s.split(",") {
-            if source == "osrm" {
-                let result = service::call_osrm(osrm_url.as_str(), osrm_url_default.as_str(), &client);
-                if result.0.code == 200 {      
-                    return result;
-                }
-                continue;
-            }
-            
-            if source == "smart" {
-                let result = service::call_smart(
-                    osrm_url.as_str(), 
-                    osrm_url_default.as_str(), 
-                    vietbando_url.as_str(), 
-                    vietbando_api_key.as_str(),
-                    boundary.as_str(), &client);
-                if result.0.code >= 400 {
-                    continue;
-                }
-                return result;
-            }
+    let limit_timeout = get_limit_timeout(config);
+    let deadline = new_deadline(config, limit_timeout, timeout, timeout_header.0);
 
-            if source == "vietbando" {
-                let result = service::call_vietbando(vietbando_url.as_str(), vietbando_api_key.as_str(), boundary.as_str(), &client);
-                if result.0.code >= 400 {
-                    continue;
-                }
-                return result;
-            }
+    isochrones.compute(origin, &minutes, &state.pool, &new_client(limit_timeout), &deadline)
+}
 
-            if source == "google" {
-                let result = service::call_google(google_url.as_str(), google_api_key.as_str(), boundary.as_str(), &client);
-                if result.0.code >= 400 {
-                    continue;
-                }
-                return result;
-            }
+#[get("/shadow/report?<reset>")]
+fn get_shadow_report(shadow: State<Shadow>, reset: Option<bool>) -> JsonValue {
+    shadow.report(reset.unwrap_or(false))
+}
+
+#[post("/feedback", format = "json", data = "<feedback>")]
+fn post_feedback(calibration: State<Calibration>, regions: State<Regions>, feedback: Json<model::Feedback>) -> status::Custom<JsonValue> {
+    let mut feedback = feedback.into_inner();
+    if feedback.region.is_none() {
+        if let Some(boundary) = &feedback.boundary {
+            feedback.region = regions.locate(&utils::get_waypoints(boundary.as_str())).map(|r| r.name.clone());
         }
     }
 
-    if let Ok(d) = config.get_str("default_source") {
-        if d == "vietbando" {
-            return service::call_vietbando(vietbando_url.as_str(), vietbando_api_key.as_str(), boundary.as_str(), &client);
-        }
+    match calibration.record(feedback) {
+        Ok(()) => utils::format_response("ok", Status::Ok),
+        Err(err) => utils::format_response(err.as_str(), Status::BadRequest),
+    }
+}
 
-        if d == "smart" {
-            return service::call_smart(
-                osrm_url.as_str(), 
-                osrm_url_default.as_str(), 
-                vietbando_url.as_str(), 
-                vietbando_api_key.as_str(),
-                boundary.as_str(), &client);
-        }
+#[get("/feedback/calibration")]
+fn get_calibration(calibration: State<Calibration>) -> JsonValue {
+    calibration.status()
+}
 
-        if d == "google" {
-            return service::call_google(
-                google_url.as_str(), 
-                google_api_key.as_str(), 
-                boundary.as_str(), &client);
-        }
-    };
-    
-    return service::call_osrm(osrm_url.as_str(), osrm_url_default.as_str(), &client)
+#[get("/osrm/upstreams")]
+fn get_osrm_upstreams(pool: State<OsrmPool>) -> JsonValue {
+    pool.status()
 }
 
 #[catch(404)]
@@ -216,12 +324,79 @@ fn rocket() -> rocket::Rocket {
         .attach(AdHoc::on_attach("Local Config", |rocket| {
             println!("Attaching local config.");
             let config = rocket.config().clone();
-            Ok(rocket.manage(LocalConfig(config)))
+            let unhealthy_after: u32 = match config.get_int("unhealthy_after") {
+                Ok(n) => n as u32,
+                _ => 3
+            };
+
+            let pool = OsrmPool::from_config(&config);
+            let health_check_interval: u64 = match config.get_int("osrm_health_check_interval") {
+                Ok(t) => t as u64,
+                _ => 5000
+            };
+
+            if health_check_interval > 0 {
+                let health_check_coordinates = match config.get_str("osrm_health_check_coordinates") {
+                    Ok(c) => c.to_string(),
+                    _ => String::from("105.8342,21.0278;105.8412,21.0245")
+                };
+                pool.spawn_health_checks(
+                    new_client(get_limit_timeout(&config)),
+                    Duration::from_millis(health_check_interval),
+                    health_check_coordinates);
+            }
+
+            let calibration = Calibration::from_config(&config);
+            let calibration_interval: u64 = match config.get_int("calibration_interval") {
+                Ok(t) => t as u64,
+                _ => 3600000
+            };
+
+            if calibration.is_enabled() && calibration_interval > 0 {
+                calibration.spawn_calibration(Duration::from_millis(calibration_interval));
+            }
+
+            let http_providers = HttpProviders::from_config(&config);
+
+            Ok(rocket
+                .manage(pool)
+                .manage(calibration)
+                .manage(OfflineRouter::from_config(&config))
+                .manage(Sessions::from_config(&config))
+                .manage(Regions::from_config(&config))
+                .manage(Profiles::from_config(&config))
+                .manage(Policy::from_config(&config, &http_providers))
+                .manage(http_providers)
+                .manage(Plausibility::from_config(&config))
+                .manage(Shadow::from_config(&config))
+                .manage(Traffic::from_config(&config))
+                .manage(ProviderHealth::new(unhealthy_after))
+                .manage(LocalConfig(config)))
         }))
-        .mount("/", routes![get_driving, get_route])
+        .mount("/", routes![get_driving, get_route, get_compare, get_shadow_report, get_osrm_upstreams, post_feedback, get_calibration, get_geocode, get_reverse, get_autocomplete, get_place, get_isochrone])
         .register(catchers![not_found])
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|a| a.as_str()) == Some("build-graph") {
+        if args.len() != 5 {
+            println!("usage: osrm-wrapper build-graph <profile> <roads.geojsonseq> <graph file>");
+            println!("export the roads of an OSM extract first:");
+            println!("  osmium tags-filter extract.osm.pbf w/highway -o roads.osm.pbf");
+            println!("  osmium export roads.osm.pbf -f geojsonseq -o roads.geojsonseq");
+            std::process::exit(2);
+        }
+
+        match offline::build_graph(args[2].as_str(), args[3].as_str(), args[4].as_str()) {
+            Ok(summary) => println!("{}", summary),
+            Err(err) => {
+                println!("build graph fail: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     rocket().launch();
 }
\ No newline at end of file
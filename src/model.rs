@@ -40,17 +40,17 @@ pub struct BodyVietBanDo{
 }
 
 impl BodyVietBanDo {
-    pub fn new(points: Vec<Location>) -> Self {
+    pub fn new(points: Vec<Location>, vehicle_type: i8, route_criteria: i8, uturn: i8, alternative: i8) -> Self {
         Self {
-            Alternative: 2,
+            Alternative: alternative,
             Distance: 1,
             Duration: 1,
             Geometry: 1,
             Instructions: 1,
             Points: points,
-            RouteCriteria: 0,
-            Uturn: 1,
-            VehicleType: 3,
+            RouteCriteria: route_criteria,
+            Uturn: uturn,
+            VehicleType: vehicle_type,
         }
     }
 }
@@ -90,4 +90,20 @@ pub struct VBDRoute {
     pub IsSuccess: bool,
     pub ResponseTime: String,
     pub Value: Option<Value>,
-}
\ No newline at end of file
+}
+
+// Completed trip reported to /feedback, predicted values are the ones the
+// wrapper returned and calibration_factor the one it applied, if any.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Feedback {
+    pub boundary: Option<String>,
+    pub region: Option<String>,
+    pub provider: String,
+    pub predicted_duration: f64,
+    pub actual_duration: f64,
+    pub predicted_distance: Option<f64>,
+    pub actual_distance: Option<f64>,
+    // unix seconds of the departure
+    pub timestamp: u64,
+    pub calibration_factor: Option<f64>,
+}
@@ -0,0 +1,685 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs;
+use std::sync::Arc;
+
+use geo::{LineString, Point};
+use geo::algorithm::haversine_distance::HaversineDistance;
+use polyline::encode_coordinates;
+use serde_json::Value;
+
+use rocket::config::Config;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket_contrib::json::JsonValue;
+
+use crate::deadline::Deadline;
+use crate::utils;
+
+const MAGIC: &[u8; 8] = b"OWGRAPH1";
+// snapping grid cell, about 1.1 km
+const CELL: f64 = 0.01;
+
+#[derive(Debug, Clone, Copy)]
+struct Edge {
+    node: u32,
+    // m
+    distance: f32,
+    // s
+    duration: f32,
+}
+
+// Road graph of one profile with forward and reverse adjacency, built by
+// `osrm-wrapper build-graph` and loaded whole into memory.
+pub struct Graph {
+    profile: String,
+    // lng, lat
+    coordinates: Vec<(f64, f64)>,
+    out_offsets: Vec<usize>,
+    out_edges: Vec<Edge>,
+    in_offsets: Vec<usize>,
+    in_edges: Vec<Edge>,
+    grid: HashMap<(i32, i32), Vec<u32>>,
+}
+
+impl Graph {
+    fn new(profile: String, coordinates: Vec<(f64, f64)>, edges: Vec<(u32, u32, f32, f32)>) -> Self {
+        let (out_offsets, out_edges) = adjacency(coordinates.len(), edges.iter().map(|e| (e.0, Edge { node: e.1, distance: e.2, duration: e.3 })));
+        let (in_offsets, in_edges) = adjacency(coordinates.len(), edges.iter().map(|e| (e.1, Edge { node: e.0, distance: e.2, duration: e.3 })));
+
+        let mut grid: HashMap<(i32, i32), Vec<u32>> = HashMap::new();
+        for (i, c) in coordinates.iter().enumerate() {
+            // only nodes with roads out or in are worth snapping to
+            if out_offsets[i] == out_offsets[i + 1] && in_offsets[i] == in_offsets[i + 1] {
+                continue;
+            }
+            grid.entry(cell(c.0, c.1)).or_insert_with(Vec::new).push(i as u32);
+        }
+
+        Self {
+            profile,
+            coordinates,
+            out_offsets,
+            out_edges,
+            in_offsets,
+            in_edges,
+            grid,
+        }
+    }
+
+    fn outgoing(&self, node: u32) -> &[Edge] {
+        &self.out_edges[self.out_offsets[node as usize]..self.out_offsets[node as usize + 1]]
+    }
+
+    fn incoming(&self, node: u32) -> &[Edge] {
+        &self.in_edges[self.in_offsets[node as usize]..self.in_offsets[node as usize + 1]]
+    }
+
+    // nearest node within `rings` grid cells of a point
+    fn snap(&self, lng: f64, lat: f64, rings: i32) -> Option<u32> {
+        let (cx, cy) = cell(lng, lat);
+        let scale = lat.to_radians().cos();
+        let mut nearest: Option<(f64, u32)> = None;
+        let mut found_ring: Option<i32> = None;
+        for r in 0..=rings {
+            // the next ring may still hold a closer node, the one after can't
+            if found_ring.map_or(false, |f| r > f + 1) {
+                break;
+            }
+
+            for x in cx - r..=cx + r {
+                for y in cy - r..=cy + r {
+                    // ring r only adds its border cells
+                    if (x - cx).abs() != r && (y - cy).abs() != r {
+                        continue;
+                    }
+
+                    for node in self.grid.get(&(x, y)).map(|n| n.as_slice()).unwrap_or(&[]) {
+                        let (nlng, nlat) = self.coordinates[*node as usize];
+                        let d = ((nlng - lng) * scale).powi(2) + (nlat - lat).powi(2);
+                        if nearest.map_or(true, |(best, _)| d < best) {
+                            nearest = Some((d, *node));
+                            found_ring = found_ring.or(Some(r));
+                        }
+                    }
+                }
+            }
+        }
+        nearest.map(|(_, n)| n)
+    }
+
+    // Bidirectional Dijkstra on duration, Err when the deadline expires.
+    fn shortest_path(&self, source: u32, target: u32, deadline: &Deadline) -> Result<Option<Vec<u32>>, ()> {
+        if source == target {
+            return Ok(Some(vec![source]));
+        }
+
+        // node -> (cost, parent) for the forward and backward searches
+        let mut labels: [HashMap<u32, (f64, u32)>; 2] = [HashMap::new(), HashMap::new()];
+        let mut heaps: [BinaryHeap<Entry>; 2] = [BinaryHeap::new(), BinaryHeap::new()];
+        labels[0].insert(source, (0.0, source));
+        labels[1].insert(target, (0.0, target));
+        heaps[0].push(Entry { cost: 0.0, node: source });
+        heaps[1].push(Entry { cost: 0.0, node: target });
+
+        let mut best = std::f64::INFINITY;
+        let mut meeting: Option<u32> = None;
+        let mut settled: u64 = 0;
+        loop {
+            let tops = (heaps[0].peek().map(|e| e.cost), heaps[1].peek().map(|e| e.cost));
+            // an exhausted side has final labels, so the other side alone bounds what is left
+            let dir = match tops {
+                (None, None) => break,
+                (Some(f), Some(b)) => {
+                    if f + b >= best {
+                        break;
+                    }
+                    if f <= b { 0 } else { 1 }
+                },
+                (Some(f), None) => {
+                    if f >= best {
+                        break;
+                    }
+                    0
+                },
+                (None, Some(b)) => {
+                    if b >= best {
+                        break;
+                    }
+                    1
+                },
+            };
+
+            let entry = match heaps[dir].pop() {
+                Some(e) => e,
+                None => break,
+            };
+
+            if labels[dir].get(&entry.node).map_or(false, |(c, _)| entry.cost > *c) {
+                continue;
+            }
+
+            settled += 1;
+            if settled % 10000 == 0 && deadline.is_expired() {
+                return Err(());
+            }
+
+            let edges = if dir == 0 { self.outgoing(entry.node) } else { self.incoming(entry.node) };
+            for edge in edges {
+                let cost = entry.cost + edge.duration as f64;
+                if labels[dir].get(&edge.node).map_or(false, |(c, _)| cost >= *c) {
+                    continue;
+                }
+
+                labels[dir].insert(edge.node, (cost, entry.node));
+                heaps[dir].push(Entry { cost, node: edge.node });
+                if let Some((other, _)) = labels[1 - dir].get(&edge.node) {
+                    if cost + other < best {
+                        best = cost + other;
+                        meeting = Some(edge.node);
+                    }
+                }
+            }
+        }
+
+        let meeting = match meeting {
+            Some(m) => m,
+            None => return Ok(None),
+        };
+
+        let mut path = vec![meeting];
+        let mut node = meeting;
+        while node != source {
+            node = labels[0][&node].1;
+            path.push(node);
+        }
+        path.reverse();
+
+        node = meeting;
+        while node != target {
+            node = labels[1][&node].1;
+            path.push(node);
+        }
+        Ok(Some(path))
+    }
+
+    // distance and duration of the fastest edge between consecutive nodes
+    fn measure(&self, path: &[u32]) -> (f64, f64) {
+        let mut distance = 0.0;
+        let mut duration = 0.0;
+        for pair in path.windows(2) {
+            let edge = self.outgoing(pair[0]).iter()
+                .filter(|e| e.node == pair[1])
+                .min_by(|a, b| a.duration.partial_cmp(&b.duration).unwrap_or(Ordering::Equal));
+            if let Some(e) = edge {
+                distance += e.distance as f64;
+                duration += e.duration as f64;
+            }
+        }
+        (distance, duration)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    cost: f64,
+    node: u32,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cost == other.cost && self.node == other.node
+    }
+}
+
+impl Eq for Entry {}
+
+// reversed so BinaryHeap pops the cheapest entry
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn cell(lng: f64, lat: f64) -> (i32, i32) {
+    ((lng / CELL).floor() as i32, (lat / CELL).floor() as i32)
+}
+
+fn adjacency(nodes: usize, edges: impl Iterator<Item = (u32, Edge)>) -> (Vec<usize>, Vec<Edge>) {
+    let mut buckets: Vec<Vec<Edge>> = vec![Vec::new(); nodes];
+    for (from, edge) in edges {
+        buckets[from as usize].push(edge);
+    }
+
+    let mut offsets = Vec::with_capacity(nodes + 1);
+    let mut flat = Vec::new();
+    offsets.push(0);
+    for bucket in buckets {
+        flat.extend(bucket);
+        offsets.push(flat.len());
+    }
+    (offsets, flat)
+}
+
+// In-process router over an optional local graph, the "offline" source.
+#[derive(Clone)]
+pub struct OfflineRouter {
+    graph: Option<Arc<Graph>>,
+    snap_rings: i32,
+}
+
+impl OfflineRouter {
+    pub fn from_config(config: &Config) -> Self {
+        let graph = match config.get_str("offline_graph") {
+            Ok(path) if !path.is_empty() => match load(path) {
+                Ok(g) => {
+                    println!("loaded {} graph with {} nodes and {} edges from {}", g.profile, g.coordinates.len(), g.out_edges.len(), path);
+                    Some(Arc::new(g))
+                },
+                Err(err) => {
+                    println!("load offline graph from {} fail: {}", path, err);
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        let snap_rings = match config.get_int("offline_snap_cells") {
+            Ok(n) if n >= 0 => n as i32,
+            _ => 2,
+        };
+
+        Self {
+            graph,
+            snap_rings,
+        }
+    }
+
+    pub fn route(&self, profile: &str, boundary: &str, deadline: &Deadline) -> status::Custom<JsonValue> {
+        let graph = match &self.graph {
+            Some(g) => g,
+            None => return utils::format_response("offline graph is not loaded", Status::ServiceUnavailable),
+        };
+
+        if graph.profile != profile {
+            return utils::unsupported("offline", format!("profile {}", profile).as_str());
+        }
+
+        let mut nodes: Vec<u32> = Vec::new();
+        for point in utils::get_waypoints(boundary) {
+            match graph.snap(point.x(), point.y(), self.snap_rings) {
+                Some(n) => nodes.push(n),
+                None => return utils::format_response(format!("no road near {},{}", point.x(), point.y()).as_str(), Status::BadRequest),
+            }
+        }
+
+        let mut path: Vec<u32> = Vec::new();
+        for pair in nodes.windows(2) {
+            let leg = match graph.shortest_path(pair[0], pair[1], deadline) {
+                Ok(Some(l)) => l,
+                Ok(None) => return utils::format_response("no route found", Status::BadRequest),
+                Err(()) => return utils::deadline_exceeded(),
+            };
+
+            let skip = if path.is_empty() { 0 } else { 1 };
+            path.extend(leg.into_iter().skip(skip));
+        }
+
+        let (distance, duration) = graph.measure(&path);
+        let line: LineString<f64> = path.iter().map(|n| graph.coordinates[*n as usize]).collect::<Vec<(f64, f64)>>().into();
+        let geometry = match encode_coordinates(line, 5) {
+            Ok(g) => g,
+            Err(err) => {
+                println!("encode offline geometry fail: {}", err);
+                return utils::format_response("encode geometry fail", Status::BadRequest);
+            }
+        };
+
+        status::Custom(
+            Status::Ok,
+            utils::format_response_third_party(geometry.as_str(), distance.round() as u64, duration.round() as u64, "offline"))
+    }
+}
+
+fn load(path: &str) -> Result<Graph, String> {
+    decode(&fs::read(path).map_err(|e| e.to_string())?)
+}
+
+fn decode(bytes: &[u8]) -> Result<Graph, String> {
+    let mut reader = Reader { bytes, position: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(String::from("not an offline graph, rebuild it with build-graph"));
+    }
+
+    let profile_len = reader.u32()? as usize;
+    let profile = String::from_utf8(reader.take(profile_len)?.to_vec()).map_err(|e| e.to_string())?;
+
+    let node_count = reader.u32()? as usize;
+    let mut coordinates = Vec::with_capacity(node_count);
+    for _ in 0..node_count {
+        coordinates.push((reader.f64()?, reader.f64()?));
+    }
+
+    let edge_count = reader.u32()? as usize;
+    let mut edges = Vec::with_capacity(edge_count);
+    for _ in 0..edge_count {
+        let edge = (reader.u32()?, reader.u32()?, reader.f32()?, reader.f32()?);
+        if edge.0 as usize >= node_count || edge.1 as usize >= node_count {
+            return Err(String::from("edge references a missing node"));
+        }
+        edges.push(edge);
+    }
+
+    Ok(Graph::new(profile, coordinates, edges))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.position + n > self.bytes.len() {
+            return Err(String::from("truncated graph file"));
+        }
+        let slice = &self.bytes[self.position..self.position + n];
+        self.position += n;
+        Ok(slice)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn f32(&mut self) -> Result<f32, String> {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(f32::from_le_bytes(buf))
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(f64::from_le_bytes(buf))
+    }
+}
+
+// km/h on a highway class, None when the profile can't use it. Vietnamese
+// expressways are closed to motorbikes, bicycles and pedestrians.
+fn speed(profile: &str, highway: &str) -> Option<f64> {
+    let road = match highway {
+        "motorway" => 90.0,
+        "trunk" => 70.0,
+        "primary" => 50.0,
+        "secondary" => 40.0,
+        "tertiary" => 35.0,
+        "motorway_link" | "trunk_link" | "primary_link" | "secondary_link" | "tertiary_link" => 30.0,
+        "unclassified" => 30.0,
+        "residential" => 25.0,
+        "service" => 15.0,
+        "living_street" => 10.0,
+        "footway" | "path" | "pedestrian" | "steps" | "cycleway" => 0.0,
+        _ => return None,
+    };
+
+    match profile {
+        "car" | "truck" if road > 0.0 => Some(road),
+        "motorbike" if road > 0.0 && !highway.starts_with("motorway") => Some(road.min(50.0)),
+        "bicycle" if !highway.starts_with("motorway") && highway != "steps" && highway != "footway" => Some(15.0),
+        "foot" if !highway.starts_with("motorway") && !highway.starts_with("trunk") => Some(5.0),
+        _ => None,
+    }
+}
+
+// Builds a graph file for a profile from the road ways of an OSM extract,
+// exported to GeoJSON with osmium since no PBF reader is bundled:
+//
+//   osmium tags-filter extract.osm.pbf w/highway -o roads.osm.pbf
+//   osmium export roads.osm.pbf -f geojsonseq -o roads.geojsonseq
+//
+// A FeatureCollection also works. Malformed lines are skipped and counted.
+pub fn build_graph(profile: &str, input: &str, output: &str) -> Result<String, String> {
+    let content = fs::read_to_string(input).map_err(|e| e.to_string())?;
+    let (features, skipped) = read_features(content.as_str())?;
+    let (coordinates, edges) = road_network(profile, &features);
+    fs::write(output, encode(profile, &coordinates, &edges)).map_err(|e| e.to_string())?;
+
+    let mut summary = format!("wrote {} graph with {} nodes and {} edges to {}", profile, coordinates.len(), edges.len(), output);
+    if let Some(first) = skipped.first() {
+        summary.push_str(format!(", skipped {} malformed lines, the first is line {}", skipped.len(), first).as_str());
+    }
+    Ok(summary)
+}
+
+// features and the 1-based numbers of lines that aren't JSON
+fn read_features(content: &str) -> Result<(Vec<Value>, Vec<usize>), String> {
+    if let Ok(Value::Object(collection)) = serde_json::from_str::<Value>(content) {
+        return match collection.get("features").and_then(|f| f.as_array()) {
+            Some(f) => Ok((f.clone(), Vec::new())),
+            None => Err(String::from("expected a FeatureCollection")),
+        };
+    }
+
+    let mut features: Vec<Value> = Vec::new();
+    let mut skipped: Vec<usize> = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        // geojsonseq may start records with a record separator
+        let line = line.trim_start_matches('\u{1e}').trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str::<Value>(line) {
+            Ok(f) => features.push(f),
+            Err(_) => skipped.push(i + 1),
+        }
+    }
+    Ok((features, skipped))
+}
+
+// coordinates and (from, to, distance, duration) edges of the ways the
+// profile can use
+fn road_network(profile: &str, features: &[Value]) -> (Vec<(f64, f64)>, Vec<(u32, u32, f32, f32)>) {
+    // coordinates rounded to 1e-7 degrees identify shared nodes
+    let mut ids: HashMap<(i64, i64), u32> = HashMap::new();
+    let mut coordinates: Vec<(f64, f64)> = Vec::new();
+    let mut edges: Vec<(u32, u32, f32, f32)> = Vec::new();
+    for feature in features.iter() {
+        let properties = &feature["properties"];
+        let highway = match properties["highway"].as_str() {
+            Some(h) => h,
+            None => continue,
+        };
+
+        let mut kmh = match speed(profile, highway) {
+            Some(s) => s,
+            None => continue,
+        };
+
+        if profile == "car" || profile == "truck" {
+            if let Some(max) = properties["maxspeed"].as_str().and_then(|m| m.trim().parse::<f64>().ok()) {
+                kmh = kmh.min(max);
+            }
+        }
+
+        let oneway = match properties["oneway"].as_str() {
+            _ if profile == "foot" => 0,
+            Some("yes") | Some("true") | Some("1") => 1,
+            Some("-1") | Some("reverse") => -1,
+            _ if highway == "motorway" || properties["junction"].as_str() == Some("roundabout") => 1,
+            _ => 0,
+        };
+
+        let lines: Vec<&Value> = match feature["geometry"]["type"].as_str() {
+            Some("LineString") => vec![&feature["geometry"]["coordinates"]],
+            Some("MultiLineString") => match feature["geometry"]["coordinates"].as_array() {
+                Some(l) => l.iter().collect(),
+                None => continue,
+            },
+            _ => continue,
+        };
+
+        for line in lines {
+            let mut previous: Option<(u32, Point<f64>)> = None;
+            for position in line.as_array().map(|p| p.as_slice()).unwrap_or(&[]) {
+                let (lng, lat) = match (position[0].as_f64(), position[1].as_f64()) {
+                    (Some(lng), Some(lat)) => (lng, lat),
+                    _ => continue,
+                };
+
+                let key = ((lng * 1e7).round() as i64, (lat * 1e7).round() as i64);
+                let id = *ids.entry(key).or_insert_with(|| {
+                    coordinates.push((lng, lat));
+                    coordinates.len() as u32 - 1
+                });
+
+                let point = Point::new(lng, lat);
+                if let Some((from, before)) = previous {
+                    if from != id {
+                        let distance = before.haversine_distance(&point);
+                        let duration = distance / (kmh / 3.6);
+                        if oneway >= 0 {
+                            edges.push((from, id, distance as f32, duration as f32));
+                        }
+                        if oneway <= 0 {
+                            edges.push((id, from, distance as f32, duration as f32));
+                        }
+                    }
+                }
+                previous = Some((id, point));
+            }
+        }
+    }
+    (coordinates, edges)
+}
+
+fn encode(profile: &str, coordinates: &[(f64, f64)], edges: &[(u32, u32, f32, f32)]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&(profile.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(profile.as_bytes());
+    bytes.extend_from_slice(&(coordinates.len() as u32).to_le_bytes());
+    for (lng, lat) in coordinates.iter() {
+        bytes.extend_from_slice(&lng.to_le_bytes());
+        bytes.extend_from_slice(&lat.to_le_bytes());
+    }
+    bytes.extend_from_slice(&(edges.len() as u32).to_le_bytes());
+    for (from, to, distance, duration) in edges.iter() {
+        bytes.extend_from_slice(&from.to_le_bytes());
+        bytes.extend_from_slice(&to.to_le_bytes());
+        bytes.extend_from_slice(&distance.to_le_bytes());
+        bytes.extend_from_slice(&duration.to_le_bytes());
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A (105.80, 21.00) - B (105.81, 21.00) primary both ways, B - C (105.81, 21.01)
+    // primary one way north, A - D (105.80, 21.01) - C residential both ways
+    const ROADS: &str = r#"{"type": "FeatureCollection", "features": [
+        {"type": "Feature", "properties": {"highway": "primary"}, "geometry": {"type": "LineString", "coordinates": [[105.80, 21.00], [105.81, 21.00]]}},
+        {"type": "Feature", "properties": {"highway": "primary", "oneway": "yes"}, "geometry": {"type": "LineString", "coordinates": [[105.81, 21.00], [105.81, 21.01]]}},
+        {"type": "Feature", "properties": {"highway": "residential"}, "geometry": {"type": "LineString", "coordinates": [[105.80, 21.00], [105.80, 21.01], [105.81, 21.01]]}},
+        {"type": "Feature", "properties": {"building": "yes"}, "geometry": {"type": "LineString", "coordinates": [[105.85, 21.05], [105.86, 21.05]]}}
+    ]}"#;
+
+    fn graph(profile: &str) -> Graph {
+        let (features, skipped) = read_features(ROADS).unwrap();
+        assert!(skipped.is_empty());
+        let (coordinates, edges) = road_network(profile, &features);
+        decode(&encode(profile, &coordinates, &edges)).unwrap()
+    }
+
+    fn node(graph: &Graph, lng: f64, lat: f64) -> u32 {
+        graph.snap(lng, lat, 2).unwrap()
+    }
+
+    #[test]
+    fn reads_geojsonseq_and_counts_malformed_lines() {
+        let content = "\u{1e}{\"type\": \"Feature\", \"properties\": {}}\nnot json\n\n{\"type\": \"Feature\", \"properties\": {}}\n{\"truncated\": \n";
+        let (features, skipped) = read_features(content).unwrap();
+        assert_eq!(features.len(), 2);
+        assert_eq!(skipped, vec![2, 5]);
+    }
+
+    #[test]
+    fn rejects_an_object_without_features() {
+        assert!(read_features("{\"type\": \"Feature\"}").is_err());
+    }
+
+    #[test]
+    fn builds_shared_nodes_and_skips_non_roads() {
+        let graph = graph("car");
+        assert_eq!(graph.profile, "car");
+        assert_eq!(graph.coordinates.len(), 4);
+        // A-B and the two residential segments both ways, B-C one way
+        assert_eq!(graph.out_edges.len(), 7);
+        assert_eq!(graph.in_edges.len(), 7);
+    }
+
+    #[test]
+    fn rejects_a_foreign_file() {
+        assert!(decode(b"NOTAGRAPH").is_err());
+        let bytes = encode("car", &[(105.8, 21.0)], &[]);
+        assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn routes_over_the_faster_road() {
+        let graph = graph("car");
+        let (a, b, c) = (node(&graph, 105.80, 21.00), node(&graph, 105.81, 21.00), node(&graph, 105.81, 21.01));
+        let path = graph.shortest_path(a, c, &Deadline::new(1000, 1000)).unwrap().unwrap();
+        assert_eq!(path, vec![a, b, c]);
+
+        let (distance, duration) = graph.measure(&path);
+        assert!((distance - 2150.0).abs() < 20.0, "distance {}", distance);
+        assert!((duration - distance / (50.0 / 3.6)).abs() < 1.0, "duration {}", duration);
+    }
+
+    #[test]
+    fn respects_one_way_roads() {
+        let graph = graph("car");
+        let (a, c, d) = (node(&graph, 105.80, 21.00), node(&graph, 105.81, 21.01), node(&graph, 105.80, 21.01));
+        let path = graph.shortest_path(c, a, &Deadline::new(1000, 1000)).unwrap().unwrap();
+        assert_eq!(path, vec![c, d, a]);
+    }
+
+    #[test]
+    fn walks_one_way_roads_both_ways() {
+        let graph = graph("foot");
+        let (b, c) = (node(&graph, 105.81, 21.00), node(&graph, 105.81, 21.01));
+        let path = graph.shortest_path(c, b, &Deadline::new(1000, 1000)).unwrap().unwrap();
+        assert_eq!(path, vec![c, b]);
+    }
+
+    #[test]
+    fn snaps_to_the_nearest_node() {
+        let graph = graph("car");
+        assert_eq!(graph.snap(105.8099, 21.0001, 2), Some(node(&graph, 105.81, 21.00)));
+        assert_eq!(graph.snap(106.5, 21.5, 2), None);
+    }
+
+    #[test]
+    fn answers_offline_routes() {
+        let router = OfflineRouter { graph: Some(Arc::new(graph("car"))), snap_rings: 2 };
+        let result = router.route("car", "105.80,21.00;105.81,21.01", &Deadline::new(1000, 1000));
+        assert_eq!(result.0, Status::Ok);
+        assert_eq!(result.1["source"], "offline");
+        assert!(result.1["routes"][0]["distance"].as_u64().unwrap() > 2100);
+
+        let result = router.route("foot", "105.80,21.00;105.81,21.01", &Deadline::new(1000, 1000));
+        assert_eq!(result.1["unsupported"], true);
+    }
+}
@@ -0,0 +1,351 @@
+use crate::model::{BodyVietBanDo, Location};
+use crate::utils;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Criteria {
+    Fastest,
+    Shortest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Avoid {
+    Tolls,
+    Highways,
+    Ferries,
+}
+
+impl Avoid {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "tolls" => Some(Avoid::Tolls),
+            "highways" => Some(Avoid::Highways),
+            "ferries" => Some(Avoid::Ferries),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Avoid::Tolls => "tolls",
+            Avoid::Highways => "highways",
+            Avoid::Ferries => "ferries",
+        }
+    }
+
+    // class names of the stock OSRM profiles, the exclude combination must
+    // be declared in the profile or OSRM rejects the request
+    fn osrm_class(&self) -> &'static str {
+        match self {
+            Avoid::Tolls => "toll",
+            Avoid::Highways => "motorway",
+            Avoid::Ferries => "ferry",
+        }
+    }
+}
+
+// VehicleType values of Vietbando's ViaRoute service
+const VIETBANDO_VEHICLE_TYPES: &[(i8, &str)] = &[(1, "bicycle"), (2, "motorbike"), (3, "car"), (4, "truck")];
+
+// Routing options as sent in the query string, validated by RouteOptions::parse.
+#[derive(FromForm, Debug)]
+pub struct RouteOptionsQuery {
+    pub criteria: Option<String>,
+    pub uturn: Option<bool>,
+    pub vehicle_type: Option<i64>,
+    // comma separated: tolls, highways, ferries
+    pub avoid: Option<String>,
+    // unix seconds or now
+    pub depart_at: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RouteOptions {
+    pub criteria: Criteria,
+    // None leaves the provider default
+    pub uturn: Option<bool>,
+    // overrides the profile's vietbando_vehicle_type
+    pub vehicle_type: Option<i8>,
+    pub avoid: Vec<Avoid>,
+    // unix seconds, None departs now without traffic from Google
+    pub depart_at: Option<u64>,
+    pub alternatives: bool,
+}
+
+impl RouteOptions {
+    pub fn parse(query: &RouteOptionsQuery, alternatives: bool) -> Result<Self, String> {
+        let criteria = match query.criteria.as_deref() {
+            None | Some("fastest") => Criteria::Fastest,
+            Some("shortest") => Criteria::Shortest,
+            Some(c) => return Err(format!("invalid criteria {}, expected fastest or shortest", c)),
+        };
+
+        let vehicle_type = match query.vehicle_type {
+            Some(v) if VIETBANDO_VEHICLE_TYPES.iter().any(|(t, _)| *t as i64 == v) => Some(v as i8),
+            Some(v) => {
+                let expected: Vec<String> = VIETBANDO_VEHICLE_TYPES.iter().map(|(t, name)| format!("{} ({})", t, name)).collect();
+                return Err(format!("invalid vehicle_type {}, expected {}", v, expected.join(", ")));
+            },
+            None => None,
+        };
+
+        let mut avoid: Vec<Avoid> = Vec::new();
+        for name in query.avoid.as_deref().unwrap_or("").split(",").map(|a| a.trim()).filter(|a| !a.is_empty()) {
+            match Avoid::parse(name) {
+                Some(a) if !avoid.contains(&a) => avoid.push(a),
+                Some(_) => {},
+                None => return Err(format!("invalid avoid {}, expected tolls, highways or ferries", name)),
+            }
+        }
+
+        let depart_at = match query.depart_at.as_deref() {
+            None => None,
+            Some("now") => Some(utils::now_secs()),
+            Some(d) => match d.parse::<u64>() {
+                Ok(t) => Some(t),
+                Err(_) => return Err(format!("invalid depart_at {}, expected unix seconds or now", d)),
+            },
+        };
+
+        Ok(Self {
+            criteria,
+            uturn: query.uturn,
+            vehicle_type,
+            avoid,
+            depart_at,
+            alternatives,
+        })
+    }
+
+    // The first option the source can't honour, if any.
+    pub fn unsupported_by(&self, source: &str) -> Option<&'static str> {
+        match source {
+            "osrm" => {
+                if self.criteria == Criteria::Shortest {
+                    return Some("shortest criteria");
+                }
+
+                if self.vehicle_type.is_some() {
+                    return Some("vehicle_type");
+                }
+                None
+            },
+            // the route request has no avoidance options
+            "vietbando" => {
+                if !self.avoid.is_empty() {
+                    return Some("avoid");
+                }
+                None
+            },
+            "mapbox" => {
+                if self.criteria == Criteria::Shortest {
+                    return Some("shortest criteria");
+                }
+
+                if self.vehicle_type.is_some() {
+                    return Some("vehicle_type");
+                }
+                None
+            },
+            // avoidances and u-turn rules need a custom model, Goong,
+            // Vietmap and templated providers take no routing options at all
+            "graphhopper" | "goong" | "vietmap" | "templated" => {
+                if self.criteria == Criteria::Shortest {
+                    return Some("shortest criteria");
+                }
+
+                if self.uturn == Some(false) {
+                    return Some("disallowing u-turns");
+                }
+
+                if self.vehicle_type.is_some() {
+                    return Some("vehicle_type");
+                }
+
+                if !self.avoid.is_empty() {
+                    return Some("avoid");
+                }
+                None
+            },
+            "valhalla" | "openrouteservice" => {
+                if self.uturn == Some(false) {
+                    return Some("disallowing u-turns");
+                }
+
+                if self.vehicle_type.is_some() {
+                    return Some("vehicle_type");
+                }
+                None
+            },
+            "here" => {
+                if self.uturn == Some(false) {
+                    return Some("disallowing u-turns");
+                }
+
+                if self.vehicle_type.is_some() {
+                    return Some("vehicle_type");
+                }
+                None
+            },
+            "google" => {
+                if self.criteria == Criteria::Shortest {
+                    return Some("shortest criteria");
+                }
+
+                if self.uturn == Some(false) {
+                    return Some("disallowing u-turns");
+                }
+
+                if self.vehicle_type.is_some() {
+                    return Some("vehicle_type");
+                }
+                None
+            },
+            _ => None,
+        }
+    }
+
+    pub fn vietbando_body(&self, points: Vec<Location>, vehicle_type: i8) -> BodyVietBanDo {
+        BodyVietBanDo::new(
+            points,
+            vehicle_type,
+            match self.criteria {
+                Criteria::Fastest => 0,
+                Criteria::Shortest => 1,
+            },
+            match self.uturn {
+                Some(false) => 0,
+                _ => 1,
+            },
+            if self.alternatives { 2 } else { 0 })
+    }
+
+    pub fn google_query(&self, mode: &str, traffic_model: &str) -> String {
+        let mut query = format!("mode={}&alternatives={}", mode, self.alternatives);
+        if let Some(depart_at) = self.depart_at {
+            query.push_str(format!("&departure_time={}&traffic_model={}", depart_at, traffic_model).as_str());
+        }
+
+        if !self.avoid.is_empty() {
+            let avoid: Vec<&str> = self.avoid.iter().map(|a| a.as_str()).collect();
+            query.push_str(format!("&avoid={}", avoid.join("|")).as_str());
+        }
+        query
+    }
+
+    // OSRM only avoids u-turns at waypoints through continue_straight
+    pub fn osrm_query(&self) -> String {
+        let mut query = format!("alternatives={}", self.alternatives);
+        if let Some(uturn) = self.uturn {
+            query.push_str(format!("&continue_straight={}", !uturn).as_str());
+        }
+
+        if !self.avoid.is_empty() {
+            query.push_str(format!("&exclude={}", self.osrm_exclude().join(",")).as_str());
+        }
+        query
+    }
+
+    // OSRM classes to exclude, sorted
+    pub fn osrm_exclude(&self) -> Vec<&'static str> {
+        let mut classes: Vec<&'static str> = self.avoid.iter().map(|a| a.osrm_class()).collect();
+        classes.sort_unstable();
+        classes
+    }
+
+    // Mapbox Directions takes the OSRM parameters, its exclude classes
+    // share the OSRM names
+    pub fn mapbox_query(&self, annotations: &str) -> String {
+        let mut query = format!("{}&geometries=polyline&overview=full", self.osrm_query());
+        if !annotations.is_empty() {
+            query.push_str(format!("&annotations={}", annotations).as_str());
+        }
+        query
+    }
+
+    pub fn graphhopper_query(&self, profile: &str) -> String {
+        let mut query = format!("profile={}&points_encoded=true&instructions=true", profile);
+        if self.alternatives {
+            query.push_str("&algorithm=alternative_route");
+        }
+        query
+    }
+
+    // Valhalla costing options, avoidances become zero preferences
+    pub fn valhalla_costing_options(&self) -> serde_json::Value {
+        let mut options = serde_json::Map::new();
+        if self.criteria == Criteria::Shortest {
+            options.insert(String::from("shortest"), true.into());
+        }
+
+        for avoid in self.avoid.iter() {
+            let name = match avoid {
+                Avoid::Tolls => "use_tolls",
+                Avoid::Highways => "use_highways",
+                Avoid::Ferries => "use_ferry",
+            };
+            options.insert(String::from(name), 0.0.into());
+        }
+        serde_json::Value::Object(options)
+    }
+
+    // HERE v8 route query, durations are traffic aware at departureTime
+    // or now
+    pub fn here_query(&self, transport_mode: &str) -> String {
+        let mut query = format!("transportMode={}&return=polyline,summary", transport_mode);
+        if self.criteria == Criteria::Shortest {
+            query.push_str("&routingMode=short");
+        }
+
+        if self.alternatives {
+            query.push_str("&alternatives=2");
+        }
+
+        if !self.avoid.is_empty() {
+            let features: Vec<&str> = self.avoid.iter().map(|a| match a {
+                Avoid::Tolls => "tollRoad",
+                Avoid::Highways => "controlledAccessHighway",
+                Avoid::Ferries => "ferry",
+            }).collect();
+            query.push_str(format!("&avoid[features]={}", features.join(",")).as_str());
+        }
+
+        if let Some(depart_at) = self.depart_at {
+            query.push_str(format!("&departureTime={}", utils::format_rfc3339(depart_at)).as_str());
+        }
+        query
+    }
+
+    // OpenRouteService directions body, alternatives are only computed
+    // between two waypoints
+    pub fn openrouteservice_body(&self, coordinates: Vec<serde_json::Value>) -> serde_json::Value {
+        let two_points = coordinates.len() == 2;
+        let mut body: serde_json::Value = json!({
+            "coordinates": coordinates,
+            "instructions": true,
+            "preference": match self.criteria {
+                Criteria::Fastest => "fastest",
+                Criteria::Shortest => "shortest",
+            },
+        }).into();
+
+        if self.alternatives && two_points {
+            body["alternative_routes"] = json!({ "target_count": 2 }).into();
+        }
+
+        if !self.avoid.is_empty() {
+            let features: Vec<&str> = self.avoid.iter().map(|a| match a {
+                Avoid::Tolls => "tollways",
+                Avoid::Highways => "highways",
+                Avoid::Ferries => "ferries",
+            }).collect();
+            body["options"] = json!({ "avoid_features": features }).into();
+        }
+        body
+    }
+
+    // Avoidances a successful provider honoured, providers that can't honour
+    // one are rejected by unsupported_by so every requested one applies.
+    pub fn avoided(&self) -> Vec<&'static str> {
+        self.avoid.iter().map(|a| a.as_str()).collect()
+    }
+}
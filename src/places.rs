@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use geo::Point;
+use reqwest::blocking::Client;
+
+use rocket::config::Config;
+use rocket::http::Status;
+use rocket::response::status;
+use rocket_contrib::json::JsonValue;
+
+use crate::deadline::Deadline;
+use crate::{geocode, regions, utils};
+
+// Autocomplete session tokens handed to clients, a session lasts from the
+// first keystroke until its place details are fetched or it expires.
+pub struct Sessions {
+    tokens: Mutex<HashMap<String, Instant>>,
+    ttl: Duration,
+    random: RandomState,
+}
+
+impl Sessions {
+    pub fn from_config(config: &Config) -> Self {
+        let ttl = match config.get_int("autocomplete_session_ttl") {
+            Ok(t) if t > 0 => t as u64,
+            _ => 180000,
+        };
+
+        Self {
+            tokens: Mutex::new(HashMap::new()),
+            ttl: Duration::from_millis(ttl),
+            random: RandomState::new(),
+        }
+    }
+
+    fn new_token(&self) -> String {
+        let mut token = String::new();
+        for part in 0..2u8 {
+            let mut hasher = self.random.build_hasher();
+            hasher.write_u8(part);
+            hasher.write_u128(utils::now_nanos());
+            token.push_str(format!("{:016x}", hasher.finish()).as_str());
+        }
+        token
+    }
+
+    // The client's token while it is live, otherwise a new one.
+    pub fn open(&self, token: Option<&str>) -> String {
+        let mut tokens = match self.tokens.lock() {
+            Ok(t) => t,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let ttl = self.ttl;
+        tokens.retain(|_, started| started.elapsed() < ttl);
+        if let Some(t) = token {
+            if tokens.contains_key(t) {
+                return t.to_string();
+            }
+        }
+
+        let token = self.new_token();
+        tokens.insert(token.clone(), Instant::now());
+        token
+    }
+
+    // The token while its session is live, None when unknown or expired.
+    pub fn live(&self, token: &str) -> Option<String> {
+        let tokens = match self.tokens.lock() {
+            Ok(t) => t,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        match tokens.get(token) {
+            Some(started) if started.elapsed() < self.ttl => Some(token.to_string()),
+            _ => None,
+        }
+    }
+
+    // Ends a session, None when the token was unknown or expired.
+    pub fn close(&self, token: &str) -> Option<String> {
+        let mut tokens = match self.tokens.lock() {
+            Ok(t) => t,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        match tokens.remove(token) {
+            Some(started) if started.elapsed() < self.ttl => Some(token.to_string()),
+            _ => None,
+        }
+    }
+}
+
+// Goong mirrors the Google Places autocomplete and details formats.
+pub struct Places {
+    pub sources: Vec<String>,
+    goong_autocomplete_url: String,
+    goong_details_url: String,
+    goong_api_key: String,
+    google_autocomplete_url: String,
+    google_details_url: String,
+    google_api_key: String,
+    // m around near
+    radius: i64,
+}
+
+impl Places {
+    pub fn from_config(config: &Config, sources: Option<&str>) -> Self {
+        let get = |name: &str| match config.get_str(name) {
+            Ok(v) => v.to_string(),
+            _ => String::from(""),
+        };
+
+        let sources = match sources {
+            Some(s) => regions::split_sources(s),
+            None => match config.get_str("autocomplete_sources") {
+                Ok(s) if !s.is_empty() => regions::split_sources(s),
+                _ => vec![String::from("goong"), String::from("google")],
+            },
+        };
+
+        let radius = match config.get_int("autocomplete_radius") {
+            Ok(r) if r > 0 => r,
+            _ => 50000,
+        };
+
+        Self {
+            sources,
+            goong_autocomplete_url: get("goong_autocomplete_url"),
+            goong_details_url: get("goong_details_url"),
+            goong_api_key: get("goong_api_key"),
+            google_autocomplete_url: get("google_autocomplete_url"),
+            google_details_url: get("google_details_url"),
+            google_api_key: get("google_api_key"),
+            radius,
+        }
+    }
+
+    // (autocomplete url, details url, key query name, key) of a source
+    fn provider(&self, source: &str) -> Option<(&str, &str, &str, &str)> {
+        let provider = match source {
+            "goong" => (self.goong_autocomplete_url.as_str(), self.goong_details_url.as_str(), "api_key", self.goong_api_key.as_str()),
+            "google" => (self.google_autocomplete_url.as_str(), self.google_details_url.as_str(), "key", self.google_api_key.as_str()),
+            _ => return None,
+        };
+
+        if provider.0.is_empty() || provider.1.is_empty() || provider.3.is_empty() {
+            return None;
+        }
+        Some(provider)
+    }
+
+    // Suggestions from the first provider with any, biased towards near.
+    pub fn autocomplete(&self, input: &str, near: Option<Point<f64>>, session: &str, client: &Client, deadline: &Deadline) -> status::Custom<JsonValue> {
+        let location = near.map(|p| format!("{},{}", p.y(), p.x()));
+
+        let mut result = utils::format_response("no autocomplete provider configured", Status::BadRequest);
+        for source in self.sources.iter() {
+            let (url, _, key_name, key) = match self.provider(source) {
+                Some(p) => p,
+                None => continue,
+            };
+
+            let timeout = match deadline.next_timeout() {
+                Some(t) => t,
+                None => return utils::deadline_exceeded(),
+            };
+
+            let mut query: Vec<(&str, String)> = vec![("input", input.to_string()), ("sessiontoken", session.to_string()), (key_name, key.to_string())];
+            if let Some(l) = &location {
+                query.push(("location", l.clone()));
+                query.push(("radius", self.radius.to_string()));
+            }
+
+            let body = match client.get(url).query(&query).timeout(timeout).send()
+                .and_then(|r| r.error_for_status())
+                .and_then(|r| r.json::<serde_json::Value>()) {
+                Ok(b) => b,
+                Err(err) => {
+                    println!("call {} autocomplete fail: {}", source, utils::without_url(&err));
+                    result = utils::format_response("request fail", Status::BadRequest);
+                    continue;
+                }
+            };
+
+            let suggestions: Vec<serde_json::Value> = body["predictions"].as_array().map(|p| p.as_slice()).unwrap_or(&[]).iter()
+                .map(|p| json!({
+                    "place_id": p["place_id"],
+                    "description": p["description"],
+                    "main_text": p["structured_formatting"]["main_text"],
+                    "secondary_text": p["structured_formatting"]["secondary_text"],
+                }).into())
+                .collect();
+
+            if suggestions.is_empty() {
+                result = utils::format_response("no result", Status::NotFound);
+                continue;
+            }
+
+            return status::Custom(Status::Ok, json!({
+                "code": "Ok",
+                "suggestions": suggestions,
+                "session": session,
+                "source": source,
+            }));
+        }
+
+        result.1["session"] = json!(session).into();
+        result
+    }
+
+    // Details of a suggestion from the provider that made it, sending the
+    // session token bills the whole session as one lookup.
+    pub fn details(&self, source: &str, place_id: &str, session: Option<&str>, client: &Client, deadline: &Deadline) -> status::Custom<JsonValue> {
+        let (_, url, key_name, key) = match self.provider(source) {
+            Some(p) => p,
+            None => return utils::format_response(format!("{} is not configured for places", source).as_str(), Status::BadRequest),
+        };
+
+        let timeout = match deadline.next_timeout() {
+            Some(t) => t,
+            None => return utils::deadline_exceeded(),
+        };
+
+        let mut query: Vec<(&str, &str)> = vec![("place_id", place_id), (key_name, key)];
+        if source == "google" {
+            query.push(("fields", "formatted_address,geometry,name,place_id"));
+        }
+
+        if let Some(s) = session {
+            query.push(("sessiontoken", s));
+        }
+
+        let body = match client.get(url).query(&query).timeout(timeout).send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.json::<serde_json::Value>()) {
+            Ok(b) => b,
+            Err(err) => {
+                println!("call {} place details fail: {}", source, utils::without_url(&err));
+                return utils::format_response("request fail", Status::BadRequest);
+            }
+        };
+
+        let place = &body["result"];
+        match geocode::address(
+            place["formatted_address"].as_str().unwrap_or(""),
+            place["name"].as_str(),
+            place["geometry"]["location"]["lat"].as_f64(),
+            place["geometry"]["location"]["lng"].as_f64(),
+            place["place_id"].as_str().map(|id| id.to_string()),
+        ) {
+            Some(a) => status::Custom(Status::Ok, json!({
+                "code": "Ok",
+                "result": a,
+                "source": source,
+            })),
+            None => utils::format_response("no result", Status::NotFound),
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+
+use geo::Point;
+use geo::algorithm::haversine_distance::HaversineDistance;
+use polyline::decode_polyline;
+
+use rocket::config::{Config, Value};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket_contrib::json::JsonValue;
+
+use crate::utils;
+
+// below this straight-line distance (m) the detour ratio is meaningless
+const MIN_RATIO_DISTANCE: f64 = 200.0;
+
+pub struct Plausibility {
+    min_ratio: f64,
+    max_ratio: f64,
+    endpoint_tolerance: f64,
+    // km/h
+    speed_bounds: HashMap<String, (f64, f64)>,
+}
+
+impl Plausibility {
+    pub fn from_config(config: &Config) -> Self {
+        let mut speed_bounds: HashMap<String, (f64, f64)> = HashMap::new();
+        if let Ok(table) = config.get_table("route_speed_bounds") {
+            for (profile, bounds) in table {
+                match parse_bounds(bounds) {
+                    Some(b) => { speed_bounds.insert(profile.to_string(), b); },
+                    None => println!("skip route_speed_bounds.{}: expected [min, max]", profile),
+                }
+            }
+        }
+
+        if !speed_bounds.contains_key("car") {
+            speed_bounds.insert(String::from("car"), (2.0, 130.0));
+        }
+
+        Self {
+            min_ratio: match config.get_float("route_min_detour_ratio") {
+                Ok(r) => r,
+                _ => 0.7,
+            },
+            max_ratio: match config.get_float("route_max_detour_ratio") {
+                Ok(r) => r,
+                _ => 10.0,
+            },
+            endpoint_tolerance: match config.get_float("route_endpoint_tolerance") {
+                Ok(t) => t,
+                _ => 500.0,
+            },
+            speed_bounds,
+        }
+    }
+
+    // Drops implausible routes from a provider response, the response is
+    // rejected with 502 when none is left so the fallback chain continues.
+    pub fn check(&self, profile: &str, boundary: &str, mut result: status::Custom<JsonValue>) -> status::Custom<JsonValue> {
+        if result.0.code >= 400 {
+            return result;
+        }
+
+        let source = match result.1["source"].as_str() {
+            Some(s) => s.to_string(),
+            None => String::from("osrm"),
+        };
+
+        let routes = match result.1["routes"].as_array() {
+            Some(r) => r.clone(),
+            None => return utils::format_response(format!("implausible route from {}: no routes", source).as_str(), Status::BadGateway),
+        };
+
+        let waypoints = utils::get_waypoints(boundary);
+        let straight_distance = utils::calc_vincenty_distance(boundary);
+
+        let mut reason = String::from("no routes");
+        let mut valid = Vec::new();
+        for route in routes {
+            match self.check_route(profile, &route, &waypoints, straight_distance) {
+                Ok(_) => valid.push(route),
+                Err(err) => reason = err,
+            }
+        }
+
+        if valid.is_empty() {
+            println!("implausible route from {}: {}", source, reason);
+            return utils::format_response(format!("implausible route from {}: {}", source, reason).as_str(), Status::BadGateway);
+        }
+
+        result.1["routes"] = serde_json::Value::Array(valid);
+        result
+    }
+
+    fn check_route(&self, profile: &str, route: &serde_json::Value, waypoints: &[Point<f64>], straight_distance: f64) -> Result<(), String> {
+        let geometry = match route["geometry"].as_str() {
+            Some(g) if !g.is_empty() => g,
+            _ => return Err(String::from("empty geometry")),
+        };
+
+        let distance = match route["distance"].as_f64() {
+            Some(d) => d,
+            None => return Err(String::from("missing distance")),
+        };
+
+        let duration = match route["duration"].as_f64() {
+            Some(d) => d,
+            None => return Err(String::from("missing duration")),
+        };
+
+        if straight_distance >= MIN_RATIO_DISTANCE {
+            let ratio = distance / straight_distance;
+            if ratio < self.min_ratio || ratio > self.max_ratio {
+                return Err(format!("detour ratio {:.2} out of [{}, {}]", ratio, self.min_ratio, self.max_ratio));
+            }
+        }
+
+        if distance > 0.0 {
+            if duration <= 0.0 {
+                return Err(String::from("zero duration"));
+            }
+
+            if let Some((min, max)) = self.speed_bounds.get(profile) {
+                let speed = distance / duration * 3.6;
+                if speed < *min || speed > *max {
+                    return Err(format!("average speed {:.1} km/h out of [{}, {}]", speed, min, max));
+                }
+            }
+        }
+
+        let line = match decode_polyline(geometry, 5) {
+            Ok(l) => l,
+            Err(err) => return Err(format!("undecodable geometry: {}", err)),
+        };
+
+        let (first, last) = match (line.0.first(), line.0.last(), waypoints.first(), waypoints.last()) {
+            (Some(start), Some(end), Some(first), Some(last)) => {
+                (Point::from(*start).haversine_distance(first), Point::from(*end).haversine_distance(last))
+            },
+            _ => return Err(String::from("empty geometry")),
+        };
+
+        if first > self.endpoint_tolerance || last > self.endpoint_tolerance {
+            return Err(format!("geometry ends {:.0} m / {:.0} m from waypoints", first, last));
+        }
+        Ok(())
+    }
+}
+
+fn parse_bounds(value: &Value) -> Option<(f64, f64)> {
+    let bounds = value.as_array()?;
+    if bounds.len() != 2 {
+        return None;
+    }
+
+    let mut parsed = Vec::new();
+    for b in bounds {
+        match b {
+            Value::Float(f) => parsed.push(*f),
+            Value::Integer(i) => parsed.push(*i as f64),
+            _ => return None,
+        }
+    }
+    Some((parsed[0], parsed[1]))
+}
@@ -0,0 +1,340 @@
+use geo::{LineString, Point, Polygon};
+use geo::algorithm::contains::Contains;
+
+use rocket::config::{Config, Table, Value};
+use rocket_contrib::json::JsonValue;
+
+use crate::health::ProviderHealth;
+use crate::regions;
+use crate::service::SOURCES;
+use crate::templated::HttpProviders;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Accept,
+    FallThrough,
+    Compare,
+}
+
+impl Action {
+    pub fn parse(action: &str) -> Option<Self> {
+        match action {
+            "accept" => Some(Action::Accept),
+            "fall_through" => Some(Action::FallThrough),
+            "compare" => Some(Action::Compare),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Accept => "accept",
+            Action::FallThrough => "fall_through",
+            Action::Compare => "compare",
+        }
+    }
+}
+
+// A rule matches when every condition it sets holds, unset conditions are ignored.
+pub struct Rule {
+    pub name: String,
+    pub min_detour_ratio: Option<f64>,
+    pub max_detour_ratio: Option<f64>,
+    pub min_distance: Option<f64>,
+    pub max_distance: Option<f64>,
+    pub region: Option<Polygon<f64>>,
+    pub hours: Option<(u32, u32)>,
+    pub healthy: Vec<String>,
+    pub action: Action,
+    // providers tried or compared instead of OSRM, empty uses smart_fallback
+    pub fallback: Vec<String>,
+}
+
+impl Rule {
+    fn from_table(index: usize, table: &Table, known: &[String]) -> Result<Self, String> {
+        let name = match table.get("name").and_then(|n| n.as_str()) {
+            Some(n) => n.to_string(),
+            None => format!("rule_{}", index),
+        };
+
+        let action = match table.get("action").and_then(|a| a.as_str()) {
+            Some(a) => match Action::parse(a) {
+                Some(action) => action,
+                None => return Err(format!("rule {}: unknown action {}", name, a)),
+            },
+            None => return Err(format!("rule {}: missing action", name)),
+        };
+
+        let region = match table.get("region") {
+            Some(r) => Some(parse_polygon(r).ok_or_else(|| format!("rule {}: invalid region", name))?),
+            None => None,
+        };
+
+        let hours = match table.get("hours").and_then(|h| h.as_array()) {
+            Some(h) if h.len() == 2 => {
+                match (h[0].as_integer(), h[1].as_integer()) {
+                    (Some(from), Some(to)) => Some((from as u32 % 24, to as u32 % 24)),
+                    _ => return Err(format!("rule {}: invalid hours", name)),
+                }
+            },
+            Some(_) => return Err(format!("rule {}: hours must be [from, to]", name)),
+            None => None,
+        };
+
+        let healthy = match table.get("healthy").and_then(|h| h.as_array()) {
+            Some(h) => h.iter().filter_map(|p| p.as_str()).map(|p| p.to_string()).collect(),
+            None => Vec::new(),
+        };
+
+        let fallback = match table.get("fallback").and_then(|f| f.as_array()) {
+            Some(f) => f.iter().filter_map(|p| p.as_str()).map(|p| p.to_string()).collect(),
+            None => Vec::new(),
+        };
+
+        if let Some(unknown) = unknown_fallback(&fallback, known) {
+            return Err(format!("rule {}: {} cannot be a fallback", name, unknown));
+        }
+
+        Ok(Self {
+            name,
+            min_detour_ratio: get_f64(table, "min_detour_ratio"),
+            max_detour_ratio: get_f64(table, "max_detour_ratio"),
+            min_distance: get_f64(table, "min_distance"),
+            max_distance: get_f64(table, "max_distance"),
+            region,
+            hours,
+            healthy,
+            action,
+            fallback,
+        })
+    }
+
+    fn matches(&self, facts: &Facts, health: &ProviderHealth) -> bool {
+        if self.min_detour_ratio.is_some() || self.max_detour_ratio.is_some() {
+            let ratio = match facts.detour_ratio {
+                Some(r) => r,
+                None => return false,
+            };
+
+            if let Some(min) = self.min_detour_ratio {
+                if ratio < min {
+                    return false;
+                }
+            }
+
+            if let Some(max) = self.max_detour_ratio {
+                if ratio >= max {
+                    return false;
+                }
+            }
+        }
+
+        if let Some(min) = self.min_distance {
+            if facts.straight_distance < min {
+                return false;
+            }
+        }
+
+        if let Some(max) = self.max_distance {
+            if facts.straight_distance >= max {
+                return false;
+            }
+        }
+
+        if let Some(region) = &self.region {
+            if !facts.waypoints.iter().all(|p| region.contains(p)) {
+                return false;
+            }
+        }
+
+        if let Some((from, to)) = self.hours {
+            let in_hours = if from <= to {
+                facts.hour >= from && facts.hour < to
+            } else {
+                facts.hour >= from || facts.hour < to
+            };
+
+            if !in_hours {
+                return false;
+            }
+        }
+
+        self.healthy.iter().all(|p| health.is_healthy(p))
+    }
+}
+
+pub struct Facts<'a> {
+    pub detour_ratio: Option<f64>,
+    pub straight_distance: f64,
+    pub waypoints: &'a [Point<f64>],
+    pub hour: u32,
+}
+
+pub struct Decision {
+    pub rule: String,
+    pub action: Action,
+    pub fallback: Vec<String>,
+}
+
+impl Decision {
+    pub fn to_json(&self, facts: &Facts) -> JsonValue {
+        json!({
+            "rule": self.rule,
+            "action": self.action.as_str(),
+            "fallback": self.fallback,
+            "detour_ratio": facts.detour_ratio,
+            "straight_distance": facts.straight_distance,
+        })
+    }
+}
+
+pub struct Policy {
+    pub rules: Vec<Rule>,
+    pub default_action: Action,
+    pub utc_offset: i64,
+    pub fallback: Vec<String>,
+}
+
+impl Policy {
+    // Without a configured smart_policy this reproduces the historical rule:
+    // switch to vietbando when an OSRM route is shorter than the straight line.
+    pub fn from_config(config: &Config, http_providers: &HttpProviders) -> Self {
+        let default_action = match config.get_str("smart_default_action") {
+            Ok(a) => match Action::parse(a) {
+                Some(action) => action,
+                None => {
+                    println!("unknown smart_default_action {}, using accept", a);
+                    Action::Accept
+                }
+            },
+            _ => Action::Accept,
+        };
+
+        let utc_offset = match config.get_int("utc_offset") {
+            Ok(o) => o,
+            _ => 7,
+        };
+
+        let known = fallback_sources(http_providers);
+        let rules = match config.get_slice("smart_policy") {
+            Ok(slice) => {
+                let mut rules: Vec<Rule> = Vec::new();
+                for (i, value) in slice.iter().enumerate() {
+                    let rule = match value.as_table() {
+                        Some(table) => Rule::from_table(i, table, &known),
+                        None => Err(format!("rule {}: must be a table", i)),
+                    };
+
+                    match rule {
+                        Ok(r) => rules.push(r),
+                        Err(err) => println!("skip smart policy {}", err),
+                    }
+                }
+                rules
+            },
+            _ => vec![Rule {
+                name: String::from("shorter_than_straight_line"),
+                min_detour_ratio: None,
+                max_detour_ratio: Some(1.0),
+                min_distance: None,
+                max_distance: None,
+                region: None,
+                hours: None,
+                healthy: Vec::new(),
+                action: Action::FallThrough,
+                fallback: Vec::new(),
+            }],
+        };
+
+        let fallback = match config.get_str("smart_fallback") {
+            Ok(f) if !f.is_empty() => {
+                let fallback = regions::split_sources(f);
+                match unknown_fallback(&fallback, &known) {
+                    Some(unknown) => {
+                        println!("{} cannot be a smart_fallback, using vietbando", unknown);
+                        vec![String::from("vietbando")]
+                    },
+                    None => fallback,
+                }
+            },
+            _ => vec![String::from("vietbando")],
+        };
+
+        Self {
+            rules,
+            default_action,
+            utc_offset,
+            fallback,
+        }
+    }
+
+    pub fn evaluate(&self, facts: &Facts, health: &ProviderHealth) -> Decision {
+        for rule in &self.rules {
+            if rule.matches(facts, health) {
+                return Decision {
+                    rule: rule.name.clone(),
+                    action: rule.action.clone(),
+                    fallback: if rule.fallback.is_empty() { self.fallback.clone() } else { rule.fallback.clone() },
+                };
+            }
+        }
+
+        Decision {
+            rule: String::from("default"),
+            action: self.default_action.clone(),
+            fallback: self.fallback.clone(),
+        }
+    }
+}
+
+// Providers smart can fall back to: osrm already answered and smart would
+// call itself.
+fn fallback_sources(http_providers: &HttpProviders) -> Vec<String> {
+    let mut sources: Vec<String> = SOURCES.iter()
+        .filter(|s| **s != "smart" && **s != "osrm")
+        .map(|s| s.to_string())
+        .collect();
+    sources.extend(http_providers.providers.iter().map(|t| t.name.clone()));
+    sources
+}
+
+fn unknown_fallback<'a>(fallback: &'a [String], known: &[String]) -> Option<&'a str> {
+    fallback.iter().find(|f| !known.contains(f)).map(|f| f.as_str())
+}
+
+fn get_f64(table: &Table, key: &str) -> Option<f64> {
+    match table.get(key) {
+        Some(Value::Float(f)) => Some(*f),
+        Some(Value::Integer(i)) => Some(*i as f64),
+        _ => None,
+    }
+}
+
+// region = [[lng, lat], [lng, lat], ...]
+pub fn parse_polygon(value: &Value) -> Option<Polygon<f64>> {
+    let mut coordinates: Vec<(f64, f64)> = Vec::new();
+    for point in value.as_array()? {
+        let point = point.as_array()?;
+        if point.len() != 2 {
+            return None;
+        }
+
+        let lng = match &point[0] {
+            Value::Float(f) => *f,
+            Value::Integer(i) => *i as f64,
+            _ => return None,
+        };
+
+        let lat = match &point[1] {
+            Value::Float(f) => *f,
+            Value::Integer(i) => *i as f64,
+            _ => return None,
+        };
+        coordinates.push((lng, lat));
+    }
+
+    if coordinates.len() < 3 {
+        return None;
+    }
+    Some(Polygon::new(LineString::from(coordinates), vec![]))
+}
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use reqwest::blocking::Client;
+use rocket::config::{Config, Table};
+use rocket_contrib::json::JsonValue;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    WeightedRoundRobin,
+    LeastLatency,
+}
+
+impl Strategy {
+    pub fn parse(strategy: &str) -> Option<Self> {
+        match strategy {
+            "weighted_round_robin" => Some(Strategy::WeightedRoundRobin),
+            "least_latency" => Some(Strategy::LeastLatency),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Strategy::WeightedRoundRobin => "weighted_round_robin",
+            Strategy::LeastLatency => "least_latency",
+        }
+    }
+}
+
+// One OSRM instance, a weight of 0 marks a backup only used when every
+// weighted replica failed.
+pub struct Replica {
+    pub url: String,
+    weight: i64,
+    healthy: AtomicBool,
+    failures: AtomicU32,
+    // moving average, 0 until the first success
+    latency_us: AtomicU64,
+}
+
+impl Replica {
+    fn new(url: &str, weight: i64) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            weight,
+            healthy: AtomicBool::new(true),
+            failures: AtomicU32::new(0),
+            latency_us: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    fn is_backup(&self) -> bool {
+        self.weight == 0
+    }
+
+    fn latency(&self) -> u64 {
+        self.latency_us.load(Ordering::Relaxed)
+    }
+
+    fn record_success(&self, latency: Duration) {
+        let sample = latency.as_micros() as u64;
+        let previous = self.latency();
+        let average = if previous == 0 { sample } else { (previous * 4 + sample) / 5 };
+        self.latency_us.store(average, Ordering::Relaxed);
+        self.failures.store(0, Ordering::Relaxed);
+
+        if !self.healthy.swap(true, Ordering::Relaxed) {
+            println!("osrm upstream {} reinstated", self.url);
+        }
+    }
+
+    fn record_failure(&self, eject_after: u32) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= eject_after && self.healthy.swap(false, Ordering::Relaxed) {
+            println!("osrm upstream {} ejected after {} failures", self.url, failures);
+        }
+    }
+}
+
+struct Group {
+    replicas: Vec<Arc<Replica>>,
+    // smooth weighted round robin state, one entry per replica
+    current: Mutex<Vec<i64>>,
+}
+
+#[derive(Clone)]
+pub struct OsrmPool {
+    groups: Arc<HashMap<String, Group>>,
+    strategy: Strategy,
+    eject_after: u32,
+}
+
+impl OsrmPool {
+    // osrm_upstreams = { driving = [{ url = "...", weight = 1 }, ...] } keyed by
+    // group, a profile or region name, when
+    // missing the legacy osrm_url / osrm_url_default pair becomes the driving
+    // group with osrm_url_default as backup.
+    pub fn from_config(config: &Config) -> Self {
+        let mut groups: HashMap<String, Group> = HashMap::new();
+
+        if let Ok(table) = config.get_table("osrm_upstreams") {
+            for (profile, replicas) in table {
+                let mut parsed: Vec<Arc<Replica>> = Vec::new();
+                for replica in replicas.as_array().map(|r| r.as_slice()).unwrap_or(&[]) {
+                    match replica.as_table().and_then(parse_replica) {
+                        Some(r) => parsed.push(Arc::new(r)),
+                        None => println!("skip osrm_upstreams.{}: expected {{ url, weight }}", profile),
+                    }
+                }
+                groups.insert(profile.to_string(), Group::new(parsed));
+            }
+        }
+
+        if !groups.contains_key("driving") {
+            let mut legacy: Vec<Arc<Replica>> = Vec::new();
+            if let Ok(url) = config.get_str("osrm_url") {
+                if !url.is_empty() {
+                    legacy.push(Arc::new(Replica::new(url, 1)));
+                }
+            }
+
+            if let Ok(url) = config.get_str("osrm_url_default") {
+                if !url.is_empty() {
+                    let weight = if legacy.is_empty() { 1 } else { 0 };
+                    legacy.push(Arc::new(Replica::new(url, weight)));
+                }
+            }
+            groups.insert(String::from("driving"), Group::new(legacy));
+        }
+
+        let strategy = match config.get_str("osrm_balancing") {
+            Ok(s) => match Strategy::parse(s) {
+                Some(strategy) => strategy,
+                None => {
+                    println!("unknown osrm_balancing {}, using weighted_round_robin", s);
+                    Strategy::WeightedRoundRobin
+                }
+            },
+            _ => Strategy::WeightedRoundRobin,
+        };
+
+        let eject_after = match config.get_int("osrm_eject_after") {
+            Ok(n) if n > 0 => n as u32,
+            _ => 3,
+        };
+
+        Self {
+            groups: Arc::new(groups),
+            strategy,
+            eject_after,
+        }
+    }
+
+    // first of the groups that is configured
+    pub fn resolve<'a>(&self, groups: &'a [String]) -> Option<&'a str> {
+        groups.iter()
+            .find(|g| self.groups.contains_key(g.as_str()))
+            .map(|g| g.as_str())
+    }
+
+    // Replicas of a group in the order they should be tried: the selected
+    // one, the other healthy ones, healthy backups, then ejected ones.
+    pub fn candidates(&self, group: &str) -> Vec<Arc<Replica>> {
+        let group = match self.groups.get(group) {
+            Some(g) => g,
+            None => return Vec::new(),
+        };
+
+        let mut ordered: Vec<Arc<Replica>> = Vec::new();
+        if let Some(i) = group.select(self.strategy) {
+            ordered.push(Arc::clone(&group.replicas[i]));
+        }
+
+        let mut rest: Vec<&Arc<Replica>> = group.replicas.iter()
+            .filter(|r| r.is_healthy() && !r.is_backup())
+            .filter(|r| !ordered.iter().any(|o| Arc::ptr_eq(o, r)))
+            .collect();
+        rest.sort_by_key(|r| r.latency());
+        ordered.extend(rest.into_iter().cloned());
+
+        ordered.extend(group.replicas.iter().filter(|r| r.is_healthy() && r.is_backup()).cloned());
+        ordered.extend(group.replicas.iter().filter(|r| !r.is_healthy()).cloned());
+        ordered
+    }
+
+    pub fn record_success(&self, replica: &Replica, latency: Duration) {
+        replica.record_success(latency);
+    }
+
+    pub fn record_failure(&self, replica: &Replica) {
+        replica.record_failure(self.eject_after);
+    }
+
+    // Probes every replica with a small route query, ejecting and
+    // reinstating them independently of live traffic.
+    pub fn spawn_health_checks(&self, client: Client, interval: Duration, coordinates: String) {
+        let pool = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            for group in pool.groups.values() {
+                for replica in &group.replicas {
+                    let started = Instant::now();
+                    let url = format!("{}/{}?overview=false", replica.url, coordinates);
+                    match client.get(url.as_str()).send() {
+                        Ok(res) if res.status().is_success() => replica.record_success(started.elapsed()),
+                        Ok(res) => {
+                            println!("osrm health check {} responded {}", replica.url, res.status());
+                            replica.record_failure(pool.eject_after);
+                        },
+                        Err(err) => {
+                            println!("osrm health check {} fail: {}", replica.url, err);
+                            replica.record_failure(pool.eject_after);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    pub fn status(&self) -> JsonValue {
+        let mut profiles = serde_json::Map::new();
+        for (profile, group) in self.groups.iter() {
+            let replicas: Vec<serde_json::Value> = group.replicas.iter().map(|r| json!({
+                "url": r.url,
+                "weight": r.weight,
+                "healthy": r.is_healthy(),
+                "failures": r.failures.load(Ordering::Relaxed),
+                "latency_ms": r.latency() as f64 / 1000.0,
+            }).into()).collect();
+            profiles.insert(profile.to_string(), serde_json::Value::Array(replicas));
+        }
+
+        json!({
+            "strategy": self.strategy.as_str(),
+            "profiles": profiles,
+        })
+    }
+}
+
+impl Group {
+    fn new(replicas: Vec<Arc<Replica>>) -> Self {
+        let current = vec![0; replicas.len()];
+        Self {
+            replicas,
+            current: Mutex::new(current),
+        }
+    }
+
+    fn select(&self, strategy: Strategy) -> Option<usize> {
+        let healthy: Vec<usize> = (0..self.replicas.len())
+            .filter(|i| self.replicas[*i].is_healthy() && !self.replicas[*i].is_backup())
+            .collect();
+
+        if healthy.is_empty() {
+            return None;
+        }
+
+        if strategy == Strategy::LeastLatency {
+            return healthy.into_iter().min_by_key(|i| self.replicas[*i].latency());
+        }
+
+        let mut current = match self.current.lock() {
+            Ok(c) => c,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for i in healthy {
+            current[i] += self.replicas[i].weight;
+            total += self.replicas[i].weight;
+            best = match best {
+                Some(b) if current[b] >= current[i] => Some(b),
+                _ => Some(i),
+            };
+        }
+
+        if let Some(b) = best {
+            current[b] -= total;
+        }
+        best
+    }
+}
+
+fn parse_replica(table: &Table) -> Option<Replica> {
+    let url = table.get("url")?.as_str()?;
+    let weight = match table.get("weight") {
+        Some(w) => w.as_integer()?,
+        None => 1,
+    };
+
+    if url.is_empty() || weight < 0 {
+        return None;
+    }
+    Some(Replica::new(url, weight))
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use rocket::config::{Config, Table};
+
+// A travel profile and how each provider expresses it, a provider without a
+// mapping does not support the profile.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    // osrm_upstreams group
+    pub osrm: String,
+    pub vietbando_vehicle_type: Option<i8>,
+    pub google_mode: Option<String>,
+    // e.g. mapbox/driving-traffic
+    pub mapbox_profile: Option<String>,
+    pub graphhopper_profile: Option<String>,
+    pub valhalla_costing: Option<String>,
+    pub here_transport_mode: Option<String>,
+    pub goong_vehicle: Option<String>,
+    pub vietmap_vehicle: Option<String>,
+    // e.g. driving-car, driving-hgv, cycling-regular
+    pub openrouteservice_profile: Option<String>,
+}
+
+impl Profile {
+    fn from_table(name: &str, table: &Table) -> Self {
+        Self {
+            name: name.to_string(),
+            osrm: match table.get("osrm").and_then(|o| o.as_str()) {
+                Some(o) => o.to_string(),
+                None => name.to_string(),
+            },
+            vietbando_vehicle_type: table.get("vietbando_vehicle_type")
+                .and_then(|v| v.as_integer())
+                .map(|v| v as i8),
+            google_mode: table.get("google_mode")
+                .and_then(|m| m.as_str())
+                .map(|m| m.to_string()),
+            mapbox_profile: table.get("mapbox_profile")
+                .and_then(|m| m.as_str())
+                .map(|m| m.to_string()),
+            graphhopper_profile: table.get("graphhopper_profile")
+                .and_then(|g| g.as_str())
+                .map(|g| g.to_string()),
+            valhalla_costing: table.get("valhalla_costing")
+                .and_then(|c| c.as_str())
+                .map(|c| c.to_string()),
+            here_transport_mode: table.get("here_transport_mode")
+                .and_then(|m| m.as_str())
+                .map(|m| m.to_string()),
+            goong_vehicle: table.get("goong_vehicle")
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_string()),
+            vietmap_vehicle: table.get("vietmap_vehicle")
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_string()),
+            openrouteservice_profile: table.get("openrouteservice_profile")
+                .and_then(|p| p.as_str())
+                .map(|p| p.to_string()),
+        }
+    }
+
+    // the only profile before profiles were configurable
+    fn legacy() -> Self {
+        Self {
+            name: String::from("car"),
+            osrm: String::from("driving"),
+            vietbando_vehicle_type: Some(3),
+            google_mode: Some(String::from("driving")),
+            mapbox_profile: Some(String::from("mapbox/driving-traffic")),
+            graphhopper_profile: Some(String::from("car")),
+            valhalla_costing: Some(String::from("auto")),
+            here_transport_mode: Some(String::from("car")),
+            goong_vehicle: Some(String::from("car")),
+            vietmap_vehicle: Some(String::from("car")),
+            openrouteservice_profile: Some(String::from("driving-car")),
+        }
+    }
+}
+
+pub struct Profiles {
+    profiles: HashMap<String, Profile>,
+    pub default: String,
+}
+
+impl Profiles {
+    // profiles = { car = { osrm = "driving", vietbando_vehicle_type = 3, google_mode = "driving" }, ... }
+    pub fn from_config(config: &Config) -> Self {
+        let mut profiles: HashMap<String, Profile> = HashMap::new();
+        if let Ok(table) = config.get_table("profiles") {
+            for (name, profile) in table {
+                match profile.as_table() {
+                    Some(t) => { profiles.insert(name.to_string(), Profile::from_table(name, t)); },
+                    None => println!("skip profiles.{}: must be a table", name),
+                }
+            }
+        }
+
+        if profiles.is_empty() {
+            let legacy = Profile::legacy();
+            profiles.insert(legacy.name.clone(), legacy);
+        }
+
+        let default = match config.get_str("default_profile") {
+            Ok(d) if profiles.contains_key(d) => d.to_string(),
+            Ok(d) => {
+                println!("unknown default_profile {}", d);
+                String::from("car")
+            },
+            _ => String::from("car"),
+        };
+
+        Self {
+            profiles,
+            default,
+        }
+    }
+
+    pub fn get(&self, name: Option<&str>) -> Result<&Profile, String> {
+        let name = name.unwrap_or(self.default.as_str());
+        match self.profiles.get(name) {
+            Some(p) => Ok(p),
+            None => Err(format!("unknown profile {}", name)),
+        }
+    }
+
+    pub fn is_default(&self, profile: &Profile) -> bool {
+        profile.name == self.default
+    }
+}
@@ -0,0 +1,164 @@
+use std::fs;
+
+use geo::{LineString, Point, Polygon};
+use geo::algorithm::contains::Contains;
+use rocket::config::Config;
+use serde_json::Value;
+
+// A geofence loaded from the regions GeoJSON, features carry their routing
+// in properties: { "name": "north", "osrm": "north", "sources": ["osrm", "vietbando"] }
+pub struct Region {
+    pub name: String,
+    // osrm_upstreams group serving the region
+    pub osrm: String,
+    // provider chain used when the request does not set sources
+    pub sources: Vec<String>,
+    polygons: Vec<Polygon<f64>>,
+}
+
+impl Region {
+    fn contains(&self, point: &Point<f64>) -> bool {
+        self.polygons.iter().any(|p| p.contains(point))
+    }
+}
+
+pub struct Regions {
+    regions: Vec<Region>,
+    // used when waypoints are not all inside one region
+    pub cross_region_osrm: String,
+    pub cross_region_sources: Vec<String>,
+}
+
+impl Regions {
+    pub fn from_config(config: &Config) -> Self {
+        let regions = match config.get_str("regions_file") {
+            Ok(path) if !path.is_empty() => match load(path) {
+                Ok(r) => {
+                    println!("loaded {} regions from {}", r.len(), path);
+                    r
+                },
+                Err(err) => {
+                    println!("load regions from {} fail: {}", path, err);
+                    Vec::new()
+                }
+            },
+            _ => Vec::new(),
+        };
+
+        let cross_region_osrm = match config.get_str("cross_region_osrm") {
+            Ok(o) => o.to_string(),
+            _ => String::from("driving"),
+        };
+
+        let cross_region_sources = match config.get_str("cross_region_sources") {
+            Ok(s) => split_sources(s),
+            _ => Vec::new(),
+        };
+
+        Self {
+            regions,
+            cross_region_osrm,
+            cross_region_sources,
+        }
+    }
+
+    // The region holding every waypoint, None for cross-region routes.
+    pub fn locate(&self, waypoints: &[Point<f64>]) -> Option<&Region> {
+        if waypoints.is_empty() {
+            return None;
+        }
+
+        self.regions.iter().find(|r| waypoints.iter().all(|p| r.contains(p)))
+    }
+
+    // region name, osrm group and provider chain for a boundary, the group
+    // is None when no regions are configured
+    pub fn route_for(&self, waypoints: &[Point<f64>]) -> (Option<&str>, Option<&str>, &[String]) {
+        match self.locate(waypoints) {
+            Some(r) => (Some(r.name.as_str()), Some(r.osrm.as_str()), r.sources.as_slice()),
+            None if self.regions.is_empty() => (None, None, &[]),
+            None => (None, Some(self.cross_region_osrm.as_str()), self.cross_region_sources.as_slice()),
+        }
+    }
+}
+
+pub fn split_sources(sources: &str) -> Vec<String> {
+    sources.split(",")
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+fn load(path: &str) -> Result<Vec<Region>, String> {
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let geojson: Value = serde_json::from_str(content.as_str()).map_err(|e| e.to_string())?;
+
+    let features = match geojson["features"].as_array() {
+        Some(f) => f,
+        None => return Err(String::from("expected a FeatureCollection")),
+    };
+
+    let mut regions: Vec<Region> = Vec::new();
+    for (i, feature) in features.iter().enumerate() {
+        let properties = &feature["properties"];
+        let name = match properties["name"].as_str() {
+            Some(n) => n.to_string(),
+            None => format!("region_{}", i),
+        };
+
+        let polygons = match parse_geometry(&feature["geometry"]) {
+            Some(p) if !p.is_empty() => p,
+            _ => {
+                println!("skip region {}: expected a Polygon or MultiPolygon", name);
+                continue;
+            }
+        };
+
+        let osrm = match properties["osrm"].as_str() {
+            Some(o) => o.to_string(),
+            None => name.clone(),
+        };
+
+        let sources = match properties["sources"].as_array() {
+            Some(s) => s.iter().filter_map(|s| s.as_str()).map(|s| s.to_string()).collect(),
+            None => Vec::new(),
+        };
+
+        regions.push(Region {
+            name,
+            osrm,
+            sources,
+            polygons,
+        });
+    }
+    Ok(regions)
+}
+
+fn parse_geometry(geometry: &Value) -> Option<Vec<Polygon<f64>>> {
+    let coordinates = geometry["coordinates"].as_array()?;
+    match geometry["type"].as_str()? {
+        "Polygon" => Some(vec![parse_polygon(coordinates)?]),
+        "MultiPolygon" => coordinates.iter()
+            .map(|p| parse_polygon(p.as_array()?))
+            .collect(),
+        _ => None,
+    }
+}
+
+fn parse_polygon(rings: &[Value]) -> Option<Polygon<f64>> {
+    let mut rings = rings.iter().map(parse_ring);
+    let exterior = rings.next()??;
+    let interiors = rings.collect::<Option<Vec<LineString<f64>>>>()?;
+    Some(Polygon::new(exterior, interiors))
+}
+
+fn parse_ring(ring: &Value) -> Option<LineString<f64>> {
+    let mut coordinates: Vec<(f64, f64)> = Vec::new();
+    for position in ring.as_array()? {
+        let lng = position[0].as_f64()?;
+        let lat = position[1].as_f64()?;
+        coordinates.push((lng, lat));
+    }
+    Some(coordinates.into())
+}
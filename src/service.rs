@@ -1,33 +1,501 @@
 use std::collections::HashMap;
-use rocket::config::Value;
+use std::time::Instant;
+use rocket::config::{Config, Value};
 use reqwest::blocking::Client;
 use rocket::http::Status;
 use rocket::response::status;
 use rocket_contrib::json::JsonValue;
 use crate::{utils, model};
+use crate::calibration::Calibration;
+use crate::deadline::Deadline;
+use crate::estimate::Estimate;
+use crate::flexpolyline;
+use crate::health::ProviderHealth;
+use crate::policy::{self, Action, Policy};
+use crate::plausibility::Plausibility;
+use crate::offline::OfflineRouter;
+use crate::options::RouteOptions;
+use crate::pool::OsrmPool;
+use crate::profiles::{Profile, Profiles};
+use crate::regions::Regions;
+use crate::shadow::Shadow;
+use crate::templated::{HttpProviders, Templated};
+use crate::traffic::Traffic;
 
-pub fn call_smart(osrm_url: &str, osrm_url_default: &str, vietbando_url: &str, vietbando_api_key: &str, boundary: &str, client: &Client) ->status::Custom<JsonValue> {
-    let result = call_osrm(osrm_url, osrm_url_default, &client);
-    if result.0.code >= 400 {
-        return result;
+// sources built in, templated providers come from http_providers
+pub const SOURCES: &[&str] = &[
+    "smart", "osrm", "vietbando", "google", "mapbox", "graphhopper", "valhalla", "here",
+    "goong", "vietmap", "openrouteservice", "estimate", "offline",
+];
+
+#[derive(Clone)]
+pub struct Upstreams {
+    // boundary and query appended to the OSRM replica urls
+    pub osrm_path: String,
+    // osrm_upstreams groups by preference, from the region and profile
+    pub osrm_groups: Vec<String>,
+    // exclude class combinations declared by each group's OSRM profile
+    pub osrm_excludes: HashMap<String, Vec<Vec<String>>>,
+    pub region: Option<String>,
+    pub profile: Profile,
+    pub options: RouteOptions,
+    // provider chain of the region, used when the request sets no sources
+    pub region_sources: Vec<String>,
+    pub osrm_shadow_url: String,
+    pub vietbando_url: String,
+    pub vietbando_api_key: String,
+    pub google_url: String,
+    pub google_api_key: String,
+    pub google_traffic_model: String,
+    pub estimate: Estimate,
+    pub mapbox_url: String,
+    pub mapbox_access_token: String,
+    pub mapbox_annotations: String,
+    pub graphhopper_url: String,
+    pub graphhopper_api_key: String,
+    pub valhalla_url: String,
+    pub here_url: String,
+    pub here_api_key: String,
+    pub goong_url: String,
+    pub goong_api_key: String,
+    pub vietmap_url: String,
+    pub vietmap_api_key: String,
+    pub openrouteservice_url: String,
+    pub openrouteservice_api_key: String,
+    // json (encoded polyline) or geojson
+    pub openrouteservice_format: String,
+    pub templated: Vec<Templated>,
+}
+
+// osrm_excludes = { driving = ["toll", "motorway", "toll,motorway"] }, each
+// combination sorted so the order in the request doesn't matter
+fn osrm_excludes(config: &Config) -> HashMap<String, Vec<Vec<String>>> {
+    let mut excludes: HashMap<String, Vec<Vec<String>>> = HashMap::new();
+    if let Ok(table) = config.get_table("osrm_excludes") {
+        for (group, sets) in table {
+            let sets: Vec<Vec<String>> = sets.as_array().map(|s| s.as_slice()).unwrap_or(&[]).iter()
+                .filter_map(|s| s.as_str())
+                .map(|s| {
+                    let mut classes: Vec<String> = s.split(',').map(|c| c.trim().to_string()).filter(|c| !c.is_empty()).collect();
+                    classes.sort_unstable();
+                    classes
+                })
+                .collect();
+            excludes.insert(group.to_string(), sets);
+        }
     }
-    
-    if let Some(routes) = result.1["routes"].as_array() {
-        let straight_distance = utils::calc_vincenty_distance(boundary);
-        for route in routes {
-            if let Some(distance) = route["distance"].as_f64() {
-                if distance < straight_distance { // meet condition => call vietbando
-                    return call_vietbando(vietbando_url, vietbando_api_key, boundary, &client);
+    excludes
+}
+
+// A region serves a profile from "<group>_<profile>", the default profile
+// may also use the bare region group.
+pub fn osrm_groups(profiles: &Profiles, profile: &Profile, region_osrm: Option<&str>) -> Vec<String> {
+    let mut groups: Vec<String> = Vec::new();
+    if let Some(group) = region_osrm {
+        groups.push(format!("{}_{}", group, profile.name));
+        if profiles.is_default(profile) {
+            groups.push(group.to_string());
+        }
+    }
+    groups.push(profile.osrm.clone());
+    groups
+}
+
+impl Upstreams {
+    pub fn from_config(config: &Config, regions: &Regions, profiles: &Profiles, http_providers: &HttpProviders, profile: Option<&str>, boundary: &str, options: RouteOptions) -> Result<Self, status::Custom<JsonValue>> {
+        let profile = match profiles.get(profile) {
+            Ok(p) => p,
+            Err(err) => return Err(utils::format_response(err.as_str(), Status::BadRequest)),
+        };
+
+        let osrm_path = format!("{}?{}", boundary, options.osrm_query());
+        let waypoints = utils::get_waypoints(boundary);
+        let (region, region_osrm, region_sources) = regions.route_for(&waypoints);
+
+        let osrm_groups = osrm_groups(profiles, profile, region_osrm);
+
+        // the candidate graph is only compared for the default profile
+        let osrm_shadow_url = match config.get_str("shadow_osrm_url") {
+            Ok(u) if !u.is_empty() && profiles.is_default(profile) => format!("{}/{}", u, osrm_path),
+            _ => String::from("")
+        };
+
+        Ok(Self {
+            osrm_path,
+            osrm_groups,
+            osrm_excludes: osrm_excludes(config),
+            region: region.map(|r| r.to_string()),
+            profile: profile.clone(),
+            options,
+            region_sources: region_sources.to_vec(),
+            osrm_shadow_url,
+            vietbando_url: get_config_string(config, "vietbando_url"),
+            vietbando_api_key: get_config_string(config, "vietbando_api_key"),
+            google_url: get_config_string(config, "google_url"),
+            google_api_key: get_config_string(config, "google_api_key"),
+            google_traffic_model: match config.get_str("google_traffic_model") {
+                Ok(m) if !m.is_empty() => m.to_string(),
+                _ => String::from("best_guess"),
+            },
+            estimate: Estimate::from_config(config, region, profile.name.as_str()),
+            mapbox_url: get_config_string(config, "mapbox_url"),
+            mapbox_access_token: get_config_string(config, "mapbox_access_token"),
+            mapbox_annotations: get_config_string(config, "mapbox_annotations"),
+            graphhopper_url: get_config_string(config, "graphhopper_url"),
+            graphhopper_api_key: get_config_string(config, "graphhopper_api_key"),
+            valhalla_url: get_config_string(config, "valhalla_url"),
+            here_url: get_config_string(config, "here_url"),
+            here_api_key: get_config_string(config, "here_api_key"),
+            goong_url: get_config_string(config, "goong_url"),
+            goong_api_key: get_config_string(config, "goong_api_key"),
+            vietmap_url: get_config_string(config, "vietmap_url"),
+            vietmap_api_key: get_config_string(config, "vietmap_api_key"),
+            openrouteservice_url: get_config_string(config, "openrouteservice_url"),
+            openrouteservice_api_key: get_config_string(config, "openrouteservice_api_key"),
+            openrouteservice_format: match config.get_str("openrouteservice_format") {
+                Ok("geojson") => String::from("geojson"),
+                _ => String::from("json"),
+            },
+            templated: http_providers.providers.clone(),
+        })
+    }
+
+    // OSRM rejects an exclude combination its profile doesn't declare
+    pub fn osrm_excludable(&self, pool: &OsrmPool) -> bool {
+        let classes = self.options.osrm_exclude();
+        if classes.is_empty() {
+            return true;
+        }
+
+        match pool.resolve(&self.osrm_groups) {
+            Some(group) => self.osrm_excludes.get(group)
+                .map_or(false, |sets| sets.iter().any(|s| s.iter().map(|c| c.as_str()).eq(classes.iter().copied()))),
+            // call_osrm reports the missing group
+            None => true,
+        }
+    }
+
+    pub fn knows(&self, source: &str) -> bool {
+        SOURCES.contains(&source) || self.templated(source).is_some()
+    }
+
+    pub fn templated(&self, source: &str) -> Option<&Templated> {
+        self.templated.iter().find(|t| t.name == source)
+    }
+
+    // providers that have what they need to be called
+    pub fn configured(&self) -> Vec<String> {
+        let mut sources = vec![String::from("osrm")];
+        if !self.vietbando_url.is_empty() && !self.vietbando_api_key.is_empty() {
+            sources.push(String::from("vietbando"));
+        }
+
+        if !self.google_url.is_empty() && !self.google_api_key.is_empty() {
+            sources.push(String::from("google"));
+        }
+
+        if !self.mapbox_url.is_empty() && !self.mapbox_access_token.is_empty() {
+            sources.push(String::from("mapbox"));
+        }
+
+        // a self-hosted GraphHopper needs no key
+        if !self.graphhopper_url.is_empty() {
+            sources.push(String::from("graphhopper"));
+        }
+
+        if !self.valhalla_url.is_empty() {
+            sources.push(String::from("valhalla"));
+        }
+
+        if !self.here_url.is_empty() && !self.here_api_key.is_empty() {
+            sources.push(String::from("here"));
+        }
+
+        if !self.goong_url.is_empty() && !self.goong_api_key.is_empty() {
+            sources.push(String::from("goong"));
+        }
+
+        if !self.vietmap_url.is_empty() && !self.vietmap_api_key.is_empty() {
+            sources.push(String::from("vietmap"));
+        }
+
+        // a self-hosted OpenRouteService needs no key
+        if !self.openrouteservice_url.is_empty() {
+            sources.push(String::from("openrouteservice"));
+        }
+        sources.extend(self.templated.iter().map(|t| t.name.clone()));
+        sources
+    }
+}
+
+fn get_config_string(config: &Config, name: &str) -> String {
+    match config.get_str(name) {
+        Ok(v) => v.to_string(),
+        _ => String::from("")
+    }
+}
+
+// Per request state shared by every provider call.
+pub struct Context<'a> {
+    pub client: &'a Client,
+    pub deadline: &'a Deadline,
+    pub policy: &'a Policy,
+    pub health: &'a ProviderHealth,
+    pub plausibility: &'a Plausibility,
+    pub shadow: &'a Shadow,
+    pub pool: &'a OsrmPool,
+    pub traffic: &'a Traffic,
+    pub calibration: &'a Calibration,
+    pub offline: &'a OfflineRouter,
+}
+
+// Calls one source by name, implausible results and failures are recorded
+// against the provider health.
+pub fn call_source(source: &str, upstreams: &Upstreams, boundary: &str, ctx: &Context) -> status::Custom<JsonValue> {
+    if source == "smart" {
+        return call_smart(upstreams, boundary, ctx);
+    }
+
+    match call_adjusted(source, upstreams, boundary, ctx) {
+        Some(result) => ctx.health.track(source, ctx.plausibility.check(upstreams.profile.name.as_str(), boundary, result)),
+        None => utils::format_response(format!("unknown source {}", source).as_str(), Status::BadRequest)
+    }
+}
+
+// Provider call with shadow traffic and duration adjustments, before the
+// plausibility check.
+fn call_adjusted(source: &str, upstreams: &Upstreams, boundary: &str, ctx: &Context) -> Option<status::Custom<JsonValue>> {
+    let result = call_provider(source, upstreams, boundary, ctx.pool, ctx.offline, ctx.client, ctx.deadline)?;
+    if source == "osrm" && result.0.code < 400 {
+        ctx.shadow.mirror(upstreams.osrm_shadow_url.as_str(), boundary, &result.1, ctx.client);
+    }
+    let result = ctx.traffic.apply(source, upstreams.region.as_deref(), upstreams.options.depart_at, result);
+    let mut result = ctx.calibration.apply(source, upstreams.region.as_deref(), upstreams.options.depart_at, result);
+    if source == "vietbando" || source == "google" {
+        utils::add_legacy_duration(&mut result);
+    }
+    Some(result)
+}
+
+// Raw provider call without checks, None when the provider is unknown.
+pub fn call_provider(source: &str, upstreams: &Upstreams, boundary: &str, pool: &OsrmPool, offline: &OfflineRouter, client: &Client, deadline: &Deadline) -> Option<status::Custom<JsonValue>> {
+    if let Some(feature) = upstreams.options.unsupported_by(source) {
+        return Some(utils::unsupported(source, feature));
+    }
+
+    let mut result = match source {
+        "osrm" => if upstreams.osrm_excludable(pool) {
+            call_osrm(pool, &upstreams.osrm_groups, upstreams.osrm_path.as_str(), client, deadline)
+        } else {
+            utils::unsupported("osrm", format!("avoid {}", upstreams.options.avoided().join(",")).as_str())
+        },
+        "vietbando" => match upstreams.options.vehicle_type.or(upstreams.profile.vietbando_vehicle_type) {
+            Some(vehicle_type) => {
+                let body = upstreams.options.vietbando_body(utils::get_location(boundary), vehicle_type);
+                call_vietbando(upstreams.vietbando_url.as_str(), upstreams.vietbando_api_key.as_str(), &body, client, deadline)
+            },
+            None => utils::unsupported("vietbando", format!("profile {}", upstreams.profile.name).as_str()),
+        },
+        "google" => match &upstreams.profile.google_mode {
+            Some(mode) => {
+                let params = upstreams.options.google_query(mode.as_str(), upstreams.google_traffic_model.as_str());
+                call_google(upstreams.google_url.as_str(), upstreams.google_api_key.as_str(), boundary, params.as_str(), client, deadline)
+            },
+            None => utils::unsupported("google", format!("profile {}", upstreams.profile.name).as_str()),
+        },
+        "mapbox" => match &upstreams.profile.mapbox_profile {
+            Some(profile) => {
+                let url = format!("{}/{}/{}?{}&access_token={}",
+                    upstreams.mapbox_url, profile, boundary,
+                    upstreams.options.mapbox_query(upstreams.mapbox_annotations.as_str()),
+                    upstreams.mapbox_access_token);
+                call_mapbox(url.as_str(), client, deadline)
+            },
+            None => utils::unsupported("mapbox", format!("profile {}", upstreams.profile.name).as_str()),
+        },
+        "graphhopper" => match &upstreams.profile.graphhopper_profile {
+            Some(profile) => {
+                let mut url = format!("{}/route?{}&{}",
+                    upstreams.graphhopper_url, utils::build_points_query(boundary),
+                    upstreams.options.graphhopper_query(profile.as_str()));
+                if !upstreams.graphhopper_api_key.is_empty() {
+                    url.push_str(format!("&key={}", upstreams.graphhopper_api_key).as_str());
+                }
+                call_graphhopper("graphhopper", url.as_str(), client, deadline)
+            },
+            None => utils::unsupported("graphhopper", format!("profile {}", upstreams.profile.name).as_str()),
+        },
+        "valhalla" => match &upstreams.profile.valhalla_costing {
+            Some(costing) => {
+                let locations: Vec<serde_json::Value> = utils::get_waypoints(boundary).iter()
+                    .map(|p| json!({ "lat": p.y(), "lon": p.x() }).into())
+                    .collect();
+                let mut costing_options = serde_json::Map::new();
+                costing_options.insert(costing.clone(), upstreams.options.valhalla_costing_options());
+
+                let body: serde_json::Value = json!({
+                    "locations": locations,
+                    "costing": costing,
+                    "costing_options": costing_options,
+                    "alternates": if upstreams.options.alternatives { 2 } else { 0 },
+                    "directions_options": { "units": "kilometers" },
+                }).into();
+                call_valhalla(format!("{}/route", upstreams.valhalla_url).as_str(), &body, client, deadline)
+            },
+            None => utils::unsupported("valhalla", format!("profile {}", upstreams.profile.name).as_str()),
+        },
+        "here" => match &upstreams.profile.here_transport_mode {
+            Some(mode) => {
+                let url = format!("{}?{}&{}&apikey={}",
+                    upstreams.here_url, utils::build_here_waypoints(boundary),
+                    upstreams.options.here_query(mode.as_str()), upstreams.here_api_key);
+                call_here(url.as_str(), client, deadline)
+            },
+            None => utils::unsupported("here", format!("profile {}", upstreams.profile.name).as_str()),
+        },
+        "goong" => match &upstreams.profile.goong_vehicle {
+            Some(vehicle) => {
+                let url = format!("{}?{}&vehicle={}&alternatives={}&api_key={}",
+                    upstreams.goong_url, utils::build_goong_waypoints(boundary), vehicle,
+                    upstreams.options.alternatives, upstreams.goong_api_key);
+                call_goong(url.as_str(), client, deadline)
+            },
+            None => utils::unsupported("goong", format!("profile {}", upstreams.profile.name).as_str()),
+        },
+        "vietmap" => match &upstreams.profile.vietmap_vehicle {
+            Some(vehicle) => {
+                let url = format!("{}?api-version=1.1&{}&vehicle={}&points_encoded=true&apikey={}",
+                    upstreams.vietmap_url, utils::build_points_query(boundary), vehicle, upstreams.vietmap_api_key);
+                call_graphhopper("vietmap", url.as_str(), client, deadline)
+            },
+            None => utils::unsupported("vietmap", format!("profile {}", upstreams.profile.name).as_str()),
+        },
+        "openrouteservice" => match &upstreams.profile.openrouteservice_profile {
+            Some(profile) => {
+                let coordinates: Vec<serde_json::Value> = utils::get_waypoints(boundary).iter()
+                    .map(|p| json!([p.x(), p.y()]).into())
+                    .collect();
+                let url = format!("{}/v2/directions/{}/{}", upstreams.openrouteservice_url, profile, upstreams.openrouteservice_format);
+                let body = upstreams.options.openrouteservice_body(coordinates);
+                call_openrouteservice(url.as_str(), upstreams.openrouteservice_api_key.as_str(), &body, client, deadline)
+            },
+            None => utils::unsupported("openrouteservice", format!("profile {}", upstreams.profile.name).as_str()),
+        },
+        "estimate" => upstreams.estimate.route(boundary),
+        "offline" => offline.route(upstreams.profile.name.as_str(), boundary, deadline),
+        _ => match upstreams.templated(source) {
+            Some(t) => t.call(boundary, upstreams.profile.name.as_str(), &upstreams.options, client, deadline),
+            None => return None,
+        },
+    };
+
+    // neither a straight line nor the local graph know what to avoid
+    if result.0.code < 400 && source != "estimate" && source != "offline" && !upstreams.options.avoid.is_empty() {
+        result.1["avoided"] = json!(upstreams.options.avoided()).into();
+    }
+    Some(result)
+}
+
+pub fn call_smart(upstreams: &Upstreams, boundary: &str, ctx: &Context) ->status::Custom<JsonValue> {
+    // the policy sees OSRM's route before plausibility does, a route shorter
+    // than the straight line is what the default rule falls through on
+    let osrm = match call_adjusted("osrm", upstreams, boundary, ctx) {
+        Some(r) => r,
+        None => return utils::format_response("unknown source osrm", Status::BadRequest),
+    };
+
+    let osrm_distance = if osrm.0.code < 400 { shortest_distance(&osrm.1) } else { None };
+    let straight_distance = utils::calc_vincenty_distance(boundary);
+    let waypoints = utils::get_waypoints(boundary);
+    let facts = policy::Facts {
+        detour_ratio: match osrm_distance {
+            Some(d) if straight_distance > 0.0 => Some(d / straight_distance),
+            _ => None,
+        },
+        straight_distance,
+        waypoints: &waypoints,
+        hour: utils::local_hour(ctx.policy.utc_offset),
+    };
+
+    let decision = ctx.policy.evaluate(&facts, ctx.health);
+    let mut smart = decision.to_json(&facts);
+
+    let mut result = ctx.health.track("osrm", ctx.plausibility.check(upstreams.profile.name.as_str(), boundary, osrm));
+    let accepted_distance = if result.0.code < 400 { shortest_distance(&result.1) } else { None };
+
+    match decision.action {
+        Action::Accept if result.0.code < 400 => {
+            result.1["smart"] = smart.into();
+            result
+        },
+        // a failed or implausible OSRM route is replaced like a rejected one
+        Action::Accept | Action::FallThrough => {
+            let mut fallback = if decision.action == Action::Accept {
+                result
+            } else {
+                utils::format_response("no smart fallback configured", Status::BadRequest)
+            };
+            for source in decision.fallback.iter() {
+                fallback = call_source(source, upstreams, boundary, ctx);
+                if fallback.0.code < 400 {
+                    fallback.1["smart"] = smart.into();
+                    break;
+                }
+            }
+            fallback
+        },
+        Action::Compare => {
+            let mut compared = serde_json::Map::new();
+            compared.insert(String::from("osrm"), json!(accepted_distance).into());
+
+            // the shortest of osrm and every fallback wins
+            let mut best: Option<(f64, status::Custom<JsonValue>)> = None;
+            for source in decision.fallback.iter() {
+                let other = call_source(source, upstreams, boundary, ctx);
+                let other_distance = if other.0.code < 400 { shortest_distance(&other.1) } else { None };
+                compared.insert(source.to_string(), json!(other_distance).into());
+
+                if let Some(d) = other_distance {
+                    if accepted_distance.map_or(true, |o| d < o) && best.as_ref().map_or(true, |(b, _)| d < *b) {
+                        best = Some((d, other));
+                    }
+                }
+            }
+            smart["compared"] = serde_json::Value::Object(compared);
+
+            match best {
+                Some((_, mut other)) => {
+                    other.1["smart"] = smart.into();
+                    other
+                },
+                None => {
+                    result.1["smart"] = smart.into();
+                    result
                 }
             }
         }
-    }    
-    return result;
+    }
 }
 
-pub fn call_google(url: &str, api_key: &str, boundary: &str, client: &Client) -> status::Custom<JsonValue> {
-    let completed_url = utils::build_google_url(url, api_key, boundary);
+fn shortest_distance(response: &JsonValue) -> Option<f64> {
+    let routes = response["routes"].as_array()?;
+    routes.iter()
+        .filter_map(|r| r["distance"].as_f64())
+        .fold(None, |min, d| match min {
+            Some(m) if m <= d => Some(m),
+            _ => Some(d),
+        })
+}
+
+pub fn call_google(url: &str, api_key: &str, boundary: &str, params: &str, client: &Client, deadline: &Deadline) -> status::Custom<JsonValue> {
+    let timeout = match deadline.next_timeout() {
+        Some(t) => t,
+        None => return utils::deadline_exceeded(),
+    };
+
+    let completed_url = utils::build_google_url(url, api_key, boundary, params);
     let resp = client.get(completed_url.as_str())
+        .timeout(timeout)
         .send();
 
     match resp {
@@ -47,7 +515,9 @@ pub fn call_google(url: &str, api_key: &str, boundary: &str, client: &Client) ->
                                 None => 0,
                             };
 
-                            let duration = match route["legs"][0]["duration"]["value"].as_integer() {
+                            // only present when a departure_time was sent
+                            let traffic = route["legs"][0].get("duration_in_traffic").and_then(|d| d.get("value"));
+                            let duration = match traffic.unwrap_or(&route["legs"][0]["duration"]["value"]).as_integer() {
                                 Some(d) => d,
                                 None => 0,
                             };
@@ -64,27 +534,30 @@ pub fn call_google(url: &str, api_key: &str, boundary: &str, client: &Client) ->
                     }
                 },
                 Err(err) => {
-                    println!("parse google api response fail: {}", err);
+                    println!("parse google api response fail: {}", utils::without_url(&err));
                     return utils::format_response("parse response fail", Status::BadRequest)
                 }
             }
         },
         Err(err) => {
-            println!("call google api fail: {}", err);
+            println!("call google api fail: {}", utils::without_url(&err));
             return utils::format_response("request fail", Status::BadRequest)
         }
     }
     return utils::format_response("not do anything", Status::Ok)
 }
 
-pub fn call_vietbando(url: &str, api_key: &str, boundary: &str, client: &Client) -> status::Custom<JsonValue> {
-    let locations = utils::get_location(boundary);
-    let body = model::BodyVietBanDo::new(locations);
+pub fn call_vietbando(url: &str, api_key: &str, body: &model::BodyVietBanDo, client: &Client, deadline: &Deadline) -> status::Custom<JsonValue> {
+    let timeout = match deadline.next_timeout() {
+        Some(t) => t,
+        None => return utils::deadline_exceeded(),
+    };
 
     let resp = client.post(url)
         .header("RegisterKey", api_key)
         .header("content-type", "application/json")
-        .json(&body)
+        .json(body)
+        .timeout(timeout)
         .send();
 
     match resp {
@@ -136,49 +609,431 @@ pub fn call_vietbando(url: &str, api_key: &str, boundary: &str, client: &Client)
             }
         },
         Err(err) => {
-            println!("call vietbando fail: {}", err);
+            println!("call vietbando fail: {}", utils::without_url(&err));
             return utils::format_response("request fail", Status::BadRequest)
         },
     }
     return utils::format_response("not do anything", Status::Ok)
 }
 
-pub fn call_osrm(url: &str, url_default: &str, client: &Client) -> status::Custom<JsonValue> {
-    if url.is_empty() {
-        return call_osrm_url_default(url_default, &client);
+// Tries the replicas of the first configured group in pool order until one answers, 4xx
+// responses are input errors and are returned without failing over.
+pub fn call_osrm(pool: &OsrmPool, groups: &[String], path: &str, client: &Client, deadline: &Deadline) -> status::Custom<JsonValue> {
+    let replicas = match pool.resolve(groups) {
+        Some(group) => pool.candidates(group),
+        None => Vec::new(),
+    };
+
+    if replicas.is_empty() {
+        return utils::format_response(format!("missing osrm upstream config for {}", groups.join(", ")).as_str(), Status::BadRequest);
+    }
+
+    let mut result = utils::format_response("request fail", Status::BadRequest);
+    for replica in replicas {
+        let timeout = match deadline.next_timeout() {
+            Some(t) => t,
+            None => return utils::deadline_exceeded(),
+        };
+
+        let started = Instant::now();
+        let url = format!("{}/{}", replica.url, path);
+        let res = match client.get(url.as_str()).timeout(timeout).send() {
+            Ok(r) => r,
+            Err(e) => {
+                pool.record_failure(&replica);
+                result = utils::err_response("request fail", Status::BadRequest, e);
+                continue;
+            }
+        };
+
+        let code = res.status().as_u16();
+        if code >= 500 {
+            println!("osrm {} responded {}", replica.url, code);
+            pool.record_failure(&replica);
+            result = utils::format_response("request fail", Status::BadRequest);
+            continue;
+        }
+
+        pool.record_success(&replica, started.elapsed());
+        return match res.json::<HashMap<String, Value>>() {
+            Ok(r) if code < 300 => status::Custom(Status::Ok, json!(r)),
+            Ok(r) => {
+                let message = match r.get("message").and_then(|m| m.as_str()) {
+                    Some(m) => m.to_string(),
+                    None => String::from("request fail"),
+                };
+                utils::format_response(message.as_str(), Status::BadRequest)
+            },
+            Err(e) => utils::err_response("parsed response fail", Status::BadRequest, e)
+        }
+    }
+    result
+}
+
+// Mapbox answers in the OSRM shape, routes keep their leg annotations
+// when some were requested.
+pub fn call_mapbox(url: &str, client: &Client, deadline: &Deadline) -> status::Custom<JsonValue> {
+    let timeout = match deadline.next_timeout() {
+        Some(t) => t,
+        None => return utils::deadline_exceeded(),
+    };
+
+    let res = match client.get(url).timeout(timeout).send() {
+        Ok(r) => r,
+        Err(err) => {
+            println!("call mapbox fail: {}", utils::without_url(&err));
+            return utils::format_response("request fail", Status::BadRequest)
+        }
+    };
+
+    let code = res.status().as_u16();
+    let body = match res.json::<serde_json::Value>() {
+        Ok(b) => b,
+        Err(err) => {
+            println!("parse mapbox response fail: {}", utils::without_url(&err));
+            return utils::format_response("parse response fail", Status::BadRequest)
+        }
+    };
+
+    if code >= 400 || body["code"].as_str() != Some("Ok") {
+        let message = match body["message"].as_str() {
+            Some(m) => m,
+            None => "request fail",
+        };
+        return utils::format_response(message, Status::BadRequest)
     }
 
-    let res = client.get(url).send();
-    if let Err(e) = res {
-        if e.is_timeout() {
-            return call_osrm_url_default(url_default, &client);
+    let routes: Vec<serde_json::Value> = body["routes"].as_array().map(|r| r.as_slice()).unwrap_or(&[]).iter().map(|route| {
+        let mut normalized = json!({
+            "distance": route["distance"].as_f64().unwrap_or(0.0).round() as u64,
+            "duration": route["duration"].as_f64().unwrap_or(0.0).round() as u64,
+            "geometry": route["geometry"].as_str().unwrap_or(""),
+        });
+
+        let annotations: Vec<&serde_json::Value> = route["legs"].as_array().map(|l| l.as_slice()).unwrap_or(&[]).iter()
+            .map(|leg| &leg["annotation"])
+            .filter(|a| !a.is_null())
+            .collect();
+        if !annotations.is_empty() {
+            normalized["annotations"] = json!(annotations).into();
         }
-        return utils::err_response("request fail", Status::BadRequest, e);
+        normalized.into()
+    }).collect();
+
+    if routes.is_empty() {
+        return utils::format_response("no route found", Status::BadRequest)
     }
 
-    if let Ok(r) = res {
-        if r.status().as_u16() >= 300 {
-            return call_osrm_url_default(url_default, &client);
+    status::Custom(Status::Ok, utils::format_routes(routes, "mapbox"))
+}
+
+// GraphHopper paths carry time in ms and points as a polyline5 when
+// points_encoded is set, instructions become the route steps. Vietmap
+// serves the same format.
+pub fn call_graphhopper(source: &str, url: &str, client: &Client, deadline: &Deadline) -> status::Custom<JsonValue> {
+    let timeout = match deadline.next_timeout() {
+        Some(t) => t,
+        None => return utils::deadline_exceeded(),
+    };
+
+    let res = match client.get(url).timeout(timeout).send() {
+        Ok(r) => r,
+        Err(err) => {
+            println!("call {} fail: {}", source, utils::without_url(&err));
+            return utils::format_response("request fail", Status::BadRequest)
         }
+    };
 
-        println!("{:?}", r);
-        return match r.json::<HashMap<String, Value>>() {
-            Ok(r) => status::Custom(Status::Ok, json!(r)),
-            Err(e) => utils::err_response("parsed response fail", Status::BadRequest, e)
+    let code = res.status().as_u16();
+    let body = match res.json::<serde_json::Value>() {
+        Ok(b) => b,
+        Err(err) => {
+            println!("parse {} response fail: {}", source, utils::without_url(&err));
+            return utils::format_response("parse response fail", Status::BadRequest)
         }
+    };
+
+    if code >= 400 {
+        let message = match body["message"].as_str() {
+            Some(m) => m,
+            None => "request fail",
+        };
+        return utils::format_response(message, Status::BadRequest)
     }
-    utils::format_response("not do anything", Status::BadRequest)
+
+    let routes: Vec<serde_json::Value> = body["paths"].as_array().map(|p| p.as_slice()).unwrap_or(&[]).iter().map(|path| {
+        let steps: Vec<serde_json::Value> = path["instructions"].as_array().map(|i| i.as_slice()).unwrap_or(&[]).iter()
+            .map(|i| utils::format_step(&i["text"], i["distance"].as_f64().unwrap_or(0.0), i["time"].as_f64().unwrap_or(0.0) / 1000.0))
+            .collect();
+
+        json!({
+            "distance": path["distance"].as_f64().unwrap_or(0.0).round() as u64,
+            "duration": (path["time"].as_f64().unwrap_or(0.0) / 1000.0).round() as u64,
+            "geometry": path["points"].as_str().unwrap_or(""),
+            "steps": steps,
+        }).into()
+    }).collect();
+
+    if routes.is_empty() {
+        return utils::format_response("no route found", Status::BadRequest)
+    }
+
+    status::Custom(Status::Ok, utils::format_routes(routes, source))
+}
+
+// Valhalla returns the trip and its alternates with one polyline6 shape per
+// leg, lengths in km and maneuvers as steps.
+pub fn call_valhalla(url: &str, body: &serde_json::Value, client: &Client, deadline: &Deadline) -> status::Custom<JsonValue> {
+    let timeout = match deadline.next_timeout() {
+        Some(t) => t,
+        None => return utils::deadline_exceeded(),
+    };
+
+    let res = match client.post(url).json(body).timeout(timeout).send() {
+        Ok(r) => r,
+        Err(err) => {
+            println!("call valhalla fail: {}", err);
+            return utils::format_response("request fail", Status::BadRequest)
+        }
+    };
+
+    let code = res.status().as_u16();
+    let body = match res.json::<serde_json::Value>() {
+        Ok(b) => b,
+        Err(err) => {
+            println!("parse valhalla response fail: {}", err);
+            return utils::format_response("parse response fail", Status::BadRequest)
+        }
+    };
+
+    if code >= 400 {
+        let message = match body["error"].as_str() {
+            Some(m) => m,
+            None => "request fail",
+        };
+        return utils::format_response(message, Status::BadRequest)
+    }
+
+    let mut trips: Vec<&serde_json::Value> = vec![&body["trip"]];
+    if let Some(alternates) = body["alternates"].as_array() {
+        trips.extend(alternates.iter().map(|a| &a["trip"]));
+    }
+
+    let routes: Vec<serde_json::Value> = trips.iter().filter(|t| !t.is_null()).map(|trip| {
+        let legs = trip["legs"].as_array().map(|l| l.as_slice()).unwrap_or(&[]);
+        let shapes: Vec<&str> = legs.iter().map(|l| l["shape"].as_str().unwrap_or("")).collect();
+        let steps: Vec<serde_json::Value> = legs.iter()
+            .flat_map(|l| l["maneuvers"].as_array().map(|m| m.as_slice()).unwrap_or(&[]).iter())
+            .map(|m| utils::format_step(&m["instruction"], m["length"].as_f64().unwrap_or(0.0) * 1000.0, m["time"].as_f64().unwrap_or(0.0)))
+            .collect();
+
+        json!({
+            "distance": (trip["summary"]["length"].as_f64().unwrap_or(0.0) * 1000.0).round() as u64,
+            "duration": trip["summary"]["time"].as_f64().unwrap_or(0.0).round() as u64,
+            "geometry": utils::decode_geometries(&shapes, 6),
+            "steps": steps,
+        }).into()
+    }).collect();
+
+    if routes.is_empty() {
+        return utils::format_response("no route found", Status::BadRequest)
+    }
+
+    status::Custom(Status::Ok, utils::format_routes(routes, "valhalla"))
 }
 
-fn call_osrm_url_default(url: &str, client: &Client) -> status::Custom<JsonValue> {
-    let res = client.get(url).send();
-    match res {
-        Ok(v) => {
-            match v.json::<HashMap<String, Value>>() {
-                Ok(r) => status::Custom(Status::Ok, json!(r)),
-                Err(e) => utils::err_response("parsed response fail", Status::BadRequest, e)
+// HERE routes have one section per leg with a flexible polyline, duration
+// includes traffic and baseDuration is free flow.
+pub fn call_here(url: &str, client: &Client, deadline: &Deadline) -> status::Custom<JsonValue> {
+    let timeout = match deadline.next_timeout() {
+        Some(t) => t,
+        None => return utils::deadline_exceeded(),
+    };
+
+    let res = match client.get(url).timeout(timeout).send() {
+        Ok(r) => r,
+        Err(err) => {
+            println!("call here fail: {}", utils::without_url(&err));
+            return utils::format_response("request fail", Status::BadRequest)
+        }
+    };
+
+    let code = res.status().as_u16();
+    let body = match res.json::<serde_json::Value>() {
+        Ok(b) => b,
+        Err(err) => {
+            println!("parse here response fail: {}", utils::without_url(&err));
+            return utils::format_response("parse response fail", Status::BadRequest)
+        }
+    };
+
+    if code >= 400 {
+        let message = match body["title"].as_str() {
+            Some(m) => m,
+            None => "request fail",
+        };
+        return utils::format_response(message, Status::BadRequest)
+    }
+
+    let mut routes: Vec<serde_json::Value> = Vec::new();
+    for route in body["routes"].as_array().map(|r| r.as_slice()).unwrap_or(&[]) {
+        let (mut distance, mut duration, mut base_duration) = (0.0, 0.0, 0.0);
+        let mut coordinates: Vec<(f64, f64)> = Vec::new();
+        for section in route["sections"].as_array().map(|s| s.as_slice()).unwrap_or(&[]) {
+            distance += section["summary"]["length"].as_f64().unwrap_or(0.0);
+            duration += section["summary"]["duration"].as_f64().unwrap_or(0.0);
+            base_duration += section["summary"]["baseDuration"].as_f64().unwrap_or(0.0);
+
+            match flexpolyline::decode(section["polyline"].as_str().unwrap_or("")) {
+                Ok(points) => {
+                    // consecutive sections share the waypoint between them
+                    let skip = if coordinates.is_empty() { 0 } else { 1 };
+                    coordinates.extend(points.into_iter().skip(skip));
+                },
+                Err(err) => {
+                    println!("decode here polyline fail: {}", err);
+                    return utils::format_response("parse response fail", Status::BadRequest)
+                }
             }
         }
-        Err(e) => utils::err_response("request fail", Status::BadRequest, e)
+
+        routes.push(json!({
+            "distance": distance.round() as u64,
+            "duration": duration.round() as u64,
+            "base_duration": base_duration.round() as u64,
+            "geometry": utils::encode_lat_lngs(&coordinates),
+        }).into());
     }
-}
\ No newline at end of file
+
+    if routes.is_empty() {
+        return utils::format_response("no route found", Status::BadRequest)
+    }
+
+    status::Custom(Status::Ok, utils::format_routes(routes, "here"))
+}
+
+// Goong follows the Google directions format, legs are summed per route.
+pub fn call_goong(url: &str, client: &Client, deadline: &Deadline) -> status::Custom<JsonValue> {
+    let timeout = match deadline.next_timeout() {
+        Some(t) => t,
+        None => return utils::deadline_exceeded(),
+    };
+
+    let res = match client.get(url).timeout(timeout).send() {
+        Ok(r) => r,
+        Err(err) => {
+            println!("call goong fail: {}", utils::without_url(&err));
+            return utils::format_response("request fail", Status::BadRequest)
+        }
+    };
+
+    let code = res.status().as_u16();
+    let body = match res.json::<serde_json::Value>() {
+        Ok(b) => b,
+        Err(err) => {
+            println!("parse goong response fail: {}", utils::without_url(&err));
+            return utils::format_response("parse response fail", Status::BadRequest)
+        }
+    };
+
+    if code >= 400 {
+        let message = match body["error"]["message"].as_str() {
+            Some(m) => m,
+            None => "request fail",
+        };
+        return utils::format_response(message, Status::BadRequest)
+    }
+
+    let routes: Vec<serde_json::Value> = body["routes"].as_array().map(|r| r.as_slice()).unwrap_or(&[]).iter().map(|route| {
+        let legs = route["legs"].as_array().map(|l| l.as_slice()).unwrap_or(&[]);
+        let distance: f64 = legs.iter().map(|l| l["distance"]["value"].as_f64().unwrap_or(0.0)).sum();
+        let duration: f64 = legs.iter().map(|l| l["duration"]["value"].as_f64().unwrap_or(0.0)).sum();
+
+        json!({
+            "distance": distance.round() as u64,
+            "duration": duration.round() as u64,
+            "geometry": route["overview_polyline"]["points"].as_str().unwrap_or(""),
+        }).into()
+    }).collect();
+
+    if routes.is_empty() {
+        return utils::format_response("no route found", Status::BadRequest)
+    }
+
+    status::Custom(Status::Ok, utils::format_routes(routes, "goong"))
+}
+
+// OpenRouteService answers routes with an encoded polyline from the json
+// endpoint or features with coordinates from the geojson one, segment steps
+// are kept in both cases.
+pub fn call_openrouteservice(url: &str, api_key: &str, body: &serde_json::Value, client: &Client, deadline: &Deadline) -> status::Custom<JsonValue> {
+    let timeout = match deadline.next_timeout() {
+        Some(t) => t,
+        None => return utils::deadline_exceeded(),
+    };
+
+    let mut req = client.post(url).json(body).timeout(timeout);
+    if !api_key.is_empty() {
+        req = req.header("Authorization", api_key);
+    }
+
+    let res = match req.send() {
+        Ok(r) => r,
+        Err(err) => {
+            println!("call openrouteservice fail: {}", err);
+            return utils::format_response("request fail", Status::BadRequest)
+        }
+    };
+
+    let code = res.status().as_u16();
+    let body = match res.json::<serde_json::Value>() {
+        Ok(b) => b,
+        Err(err) => {
+            println!("parse openrouteservice response fail: {}", err);
+            return utils::format_response("parse response fail", Status::BadRequest)
+        }
+    };
+
+    if code >= 400 {
+        let message = match body["error"]["message"].as_str().or_else(|| body["error"].as_str()) {
+            Some(m) => m,
+            None => "request fail",
+        };
+        return utils::format_response(message, Status::BadRequest)
+    }
+
+    // (route properties, geometry) from either format
+    let mut found: Vec<(&serde_json::Value, String)> = Vec::new();
+    if let Some(routes) = body["routes"].as_array() {
+        for route in routes {
+            found.push((route, route["geometry"].as_str().unwrap_or("").to_string()));
+        }
+    } else if let Some(features) = body["features"].as_array() {
+        for feature in features {
+            let coordinates: Vec<(f64, f64)> = feature["geometry"]["coordinates"].as_array().map(|c| c.as_slice()).unwrap_or(&[]).iter()
+                .filter_map(|c| Some((c[1].as_f64()?, c[0].as_f64()?)))
+                .collect();
+            found.push((&feature["properties"], utils::encode_lat_lngs(&coordinates)));
+        }
+    }
+
+    let routes: Vec<serde_json::Value> = found.into_iter().map(|(route, geometry)| {
+        let steps: Vec<serde_json::Value> = route["segments"].as_array().map(|s| s.as_slice()).unwrap_or(&[]).iter()
+            .flat_map(|s| s["steps"].as_array().map(|s| s.as_slice()).unwrap_or(&[]).iter())
+            .map(|s| utils::format_step(&s["instruction"], s["distance"].as_f64().unwrap_or(0.0), s["duration"].as_f64().unwrap_or(0.0)))
+            .collect();
+
+        json!({
+            "distance": route["summary"]["distance"].as_f64().unwrap_or(0.0).round() as u64,
+            "duration": route["summary"]["duration"].as_f64().unwrap_or(0.0).round() as u64,
+            "geometry": geometry,
+            "steps": steps,
+        }).into()
+    }).collect();
+
+    if routes.is_empty() {
+        return utils::format_response("no route found", Status::BadRequest)
+    }
+
+    status::Custom(Status::Ok, utils::format_routes(routes, "openrouteservice"))
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+use reqwest::blocking::Client;
+use rocket::config::{Config, Value};
+use rocket_contrib::json::JsonValue;
+
+use crate::similarity;
+
+// number of most divergent samples kept in the report
+const WORST_SAMPLES: usize = 10;
+
+#[derive(Default)]
+pub struct ShadowReport {
+    samples: u64,
+    failures: u64,
+    distance_delta_sum: f64,
+    distance_delta_abs_sum: f64,
+    duration_delta_sum: f64,
+    duration_delta_abs_sum: f64,
+    frechet_sum: f64,
+    frechet_max: f64,
+    shared_length_sum: f64,
+    compared_geometries: u64,
+    worst: Vec<serde_json::Value>,
+}
+
+impl ShadowReport {
+    fn record(&mut self, boundary: &str, live: &serde_json::Value, candidate: &serde_json::Value) {
+        self.samples += 1;
+
+        let distance_delta = match (candidate["distance"].as_f64(), live["distance"].as_f64()) {
+            (Some(c), Some(l)) => c - l,
+            _ => 0.0,
+        };
+
+        let duration_delta = match (candidate["duration"].as_f64(), live["duration"].as_f64()) {
+            (Some(c), Some(l)) => c - l,
+            _ => 0.0,
+        };
+
+        self.distance_delta_sum += distance_delta;
+        self.distance_delta_abs_sum += distance_delta.abs();
+        self.duration_delta_sum += duration_delta;
+        self.duration_delta_abs_sum += duration_delta.abs();
+
+        let geometry = match (live["geometry"].as_str(), candidate["geometry"].as_str()) {
+            (Some(l), Some(c)) => similarity::compare_geometries(l, c, 5),
+            _ => None,
+        };
+
+        if let Some(g) = &geometry {
+            self.compared_geometries += 1;
+            self.frechet_sum += g.frechet;
+            self.shared_length_sum += g.shared_length;
+            if g.frechet > self.frechet_max {
+                self.frechet_max = g.frechet;
+            }
+        }
+
+        self.worst.push(json!({
+            "boundary": boundary,
+            "distance_delta": distance_delta,
+            "duration_delta": duration_delta,
+            "geometry": geometry,
+        }).into());
+
+        self.worst.sort_by(|a, b| {
+            let a = a["distance_delta"].as_f64().unwrap_or(0.0).abs();
+            let b = b["distance_delta"].as_f64().unwrap_or(0.0).abs();
+            b.partial_cmp(&a).unwrap_or(std::cmp::Ordering::Equal)
+        });
+        self.worst.truncate(WORST_SAMPLES);
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mean = |sum: f64, count: u64| if count == 0 { None } else { Some(sum / count as f64) };
+
+        json!({
+            "samples": self.samples,
+            "failures": self.failures,
+            "distance_delta": {
+                "mean": mean(self.distance_delta_sum, self.samples),
+                "mean_abs": mean(self.distance_delta_abs_sum, self.samples),
+            },
+            "duration_delta": {
+                "mean": mean(self.duration_delta_sum, self.samples),
+                "mean_abs": mean(self.duration_delta_abs_sum, self.samples),
+            },
+            "geometry": {
+                "compared": self.compared_geometries,
+                "frechet_mean": mean(self.frechet_sum, self.compared_geometries),
+                "frechet_max": self.frechet_max,
+                "shared_length_mean": mean(self.shared_length_sum, self.compared_geometries),
+            },
+            "worst": self.worst,
+        })
+    }
+}
+
+// Mirrors a sampled fraction of OSRM requests to a candidate backend and
+// aggregates how its routes differ, without touching the live response.
+pub struct Shadow {
+    pub url: String,
+    rate: f64,
+    counter: AtomicU64,
+    report: Arc<Mutex<ShadowReport>>,
+}
+
+impl Shadow {
+    pub fn from_config(config: &Config) -> Self {
+        let url = match config.get_str("shadow_osrm_url") {
+            Ok(u) => u.to_string(),
+            _ => String::from(""),
+        };
+
+        let rate = match config.get_extra("shadow_sample_rate") {
+            Ok(Value::Float(r)) => *r,
+            Ok(Value::Integer(r)) => *r as f64,
+            _ => 0.0,
+        };
+
+        Self {
+            url,
+            rate: rate.max(0.0).min(1.0),
+            counter: AtomicU64::new(0),
+            report: Arc::new(Mutex::new(ShadowReport::default())),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.url.is_empty() && self.rate > 0.0
+    }
+
+    // deterministic sampling, every 1 / rate requests is mirrored
+    fn sample(&self) -> bool {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.rate).floor() > (n * self.rate).floor()
+    }
+
+    pub fn mirror(&self, candidate_url: &str, boundary: &str, live: &JsonValue, client: &Client) {
+        if !self.is_enabled() || candidate_url.is_empty() || !self.sample() {
+            return;
+        }
+
+        let live = live["routes"][0].clone();
+        let candidate_url = candidate_url.to_string();
+        let boundary = boundary.to_string();
+        let client = client.clone();
+        let report = Arc::clone(&self.report);
+
+        thread::spawn(move || {
+            let candidate = match client.get(candidate_url.as_str()).send() {
+                Ok(res) => match res.json::<HashMap<String, serde_json::Value>>() {
+                    Ok(body) => body.get("routes").map(|r| r[0].clone()),
+                    Err(err) => {
+                        println!("parse shadow osrm response fail: {}", err);
+                        None
+                    }
+                },
+                Err(err) => {
+                    println!("call shadow osrm fail: {}", err);
+                    None
+                }
+            };
+
+            let mut report = match report.lock() {
+                Ok(r) => r,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+
+            match candidate {
+                Some(c) if !c.is_null() => report.record(boundary.as_str(), &live, &c),
+                _ => report.failures += 1,
+            }
+        });
+    }
+
+    pub fn report(&self, reset: bool) -> JsonValue {
+        let mut report = match self.report.lock() {
+            Ok(r) => r,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let mut json = report.to_json();
+        json["candidate"] = json!(self.url).into();
+        json["sample_rate"] = json!(self.rate).into();
+        if reset {
+            *report = ShadowReport::default();
+        }
+        json
+    }
+}
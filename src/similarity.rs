@@ -0,0 +1,184 @@
+use geo::{Coordinate, LineString, Point};
+use geo::algorithm::euclidean_distance::EuclideanDistance;
+use polyline::decode_polyline;
+use serde::Serialize;
+
+// distance (m) under which two geometries are considered to share a road
+const SHARED_TOLERANCE: f64 = 30.0;
+// spacing (m) of the samples taken along a segment for the shared length
+const SAMPLE_STEP: f64 = 10.0;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Similarity {
+    // discrete Fréchet distance, m
+    pub frechet: f64,
+    // discrete Hausdorff distance, m
+    pub hausdorff: f64,
+    // share of the first geometry's length lying on the second one, %
+    pub shared_length: f64,
+}
+
+// Similarity between two encoded polylines.
+pub fn compare_geometries(a: &str, b: &str, precision: u32) -> Option<Similarity> {
+    let a = decode_polyline(a, precision).ok()?;
+    let b = decode_polyline(b, precision).ok()?;
+    compare_lines(&a, &b)
+}
+
+// Similarity between two lng/lat line strings, both are projected on a local
+// equirectangular plane which is accurate enough at city scale.
+pub fn compare_lines(a: &LineString<f64>, b: &LineString<f64>) -> Option<Similarity> {
+    let origin = *a.0.first()?;
+    if b.0.is_empty() {
+        return None;
+    }
+
+    let a = project(a, origin);
+    let b = project(b, origin);
+
+    Some(Similarity {
+        frechet: discrete_frechet(&a, &b),
+        hausdorff: discrete_hausdorff(&a, &b),
+        shared_length: shared_length(&a, &b),
+    })
+}
+
+fn project(line: &LineString<f64>, origin: Coordinate<f64>) -> Vec<Coordinate<f64>> {
+    let scale_x = 111_320.0 * origin.y.to_radians().cos();
+    let scale_y = 110_540.0;
+    line.0.iter()
+        .map(|c| Coordinate { x: (c.x - origin.x) * scale_x, y: (c.y - origin.y) * scale_y })
+        .collect()
+}
+
+fn distance(a: &Coordinate<f64>, b: &Coordinate<f64>) -> f64 {
+    (a.x - b.x).hypot(a.y - b.y)
+}
+
+// Eiter & Mannila, computed row by row to keep memory linear.
+fn discrete_frechet(a: &[Coordinate<f64>], b: &[Coordinate<f64>]) -> f64 {
+    let mut previous: Vec<f64> = Vec::with_capacity(b.len());
+    for (j, cb) in b.iter().enumerate() {
+        let d = distance(&a[0], cb);
+        let value = if j == 0 { d } else { previous[j - 1].max(d) };
+        previous.push(value);
+    }
+
+    for ca in a.iter().skip(1) {
+        let mut current: Vec<f64> = Vec::with_capacity(b.len());
+        for (j, cb) in b.iter().enumerate() {
+            let d = distance(ca, cb);
+            let reachable = if j == 0 {
+                previous[0]
+            } else {
+                previous[j].min(previous[j - 1]).min(current[j - 1])
+            };
+            current.push(reachable.max(d));
+        }
+        previous = current;
+    }
+
+    previous[b.len() - 1]
+}
+
+fn discrete_hausdorff(a: &[Coordinate<f64>], b: &[Coordinate<f64>]) -> f64 {
+    directed_hausdorff(a, b).max(directed_hausdorff(b, a))
+}
+
+fn directed_hausdorff(from: &[Coordinate<f64>], to: &[Coordinate<f64>]) -> f64 {
+    from.iter()
+        .map(|f| to.iter().map(|t| distance(f, t)).fold(std::f64::INFINITY, f64::min))
+        .fold(0.0, f64::max)
+}
+
+fn shared_length(a: &[Coordinate<f64>], b: &[Coordinate<f64>]) -> f64 {
+    let other: LineString<f64> = b.to_vec().into();
+    let mut total = 0.0;
+    let mut shared = 0.0;
+
+    for segment in a.windows(2) {
+        let length = distance(&segment[0], &segment[1]);
+        if length == 0.0 {
+            continue;
+        }
+
+        let samples = (length / SAMPLE_STEP).ceil().max(1.0) as usize;
+        let step = length / samples as f64;
+        for i in 0..samples {
+            let t = (i as f64 + 0.5) / samples as f64;
+            let sample = Point::new(
+                segment[0].x + (segment[1].x - segment[0].x) * t,
+                segment[0].y + (segment[1].y - segment[0].y) * t,
+            );
+
+            if sample.euclidean_distance(&other) <= SHARED_TOLERANCE {
+                shared += step;
+            }
+        }
+        total += length;
+    }
+
+    if total == 0.0 {
+        return 0.0;
+    }
+    shared / total * 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(points: &[(f64, f64)]) -> Vec<Coordinate<f64>> {
+        points.iter().map(|(x, y)| Coordinate { x: *x, y: *y }).collect()
+    }
+
+    fn assert_close(value: f64, expected: f64) {
+        assert!((value - expected).abs() < 1e-9, "{} != {}", value, expected);
+    }
+
+    #[test]
+    fn identical_lines_are_the_same_route() {
+        let a = line(&[(0.0, 0.0), (100.0, 0.0), (200.0, 50.0)]);
+        assert_close(discrete_frechet(&a, &a), 0.0);
+        assert_close(discrete_hausdorff(&a, &a), 0.0);
+        assert_close(shared_length(&a, &a), 100.0);
+    }
+
+    #[test]
+    fn parallel_lines_are_apart_by_their_offset() {
+        let a = line(&[(0.0, 0.0), (100.0, 0.0), (200.0, 0.0)]);
+        let b = line(&[(0.0, 50.0), (100.0, 50.0), (200.0, 50.0)]);
+        assert_close(discrete_frechet(&a, &b), 50.0);
+        assert_close(discrete_hausdorff(&a, &b), 50.0);
+        assert_close(shared_length(&a, &b), 0.0);
+    }
+
+    #[test]
+    fn measures_a_partial_overlap() {
+        // samples of a from x = 70 on are within the tolerance of b
+        let a = line(&[(0.0, 0.0), (200.0, 0.0)]);
+        let b = line(&[(100.0, 0.0), (300.0, 0.0)]);
+        assert_close(shared_length(&a, &b), 65.0);
+        assert_close(shared_length(&b, &a), 65.0);
+        assert_close(discrete_hausdorff(&a, &b), 100.0);
+    }
+
+    #[test]
+    fn frechet_follows_the_direction_of_travel() {
+        let a = line(&[(0.0, 0.0), (100.0, 0.0), (200.0, 0.0)]);
+        let reversed: Vec<Coordinate<f64>> = a.iter().rev().copied().collect();
+        assert_close(discrete_hausdorff(&a, &reversed), 0.0);
+        assert_close(discrete_frechet(&a, &reversed), 200.0);
+        assert_close(shared_length(&a, &reversed), 100.0);
+    }
+
+    #[test]
+    fn compares_encoded_geometries() {
+        let geometry = "_p~iF~ps|U_ulLnnqC_mqNvxq`@";
+        let similarity = compare_geometries(geometry, geometry, 5).unwrap();
+        assert_close(similarity.frechet, 0.0);
+        assert_close(similarity.hausdorff, 0.0);
+        assert_close(similarity.shared_length, 100.0);
+        assert!(compare_geometries(geometry, "", 5).is_none());
+    }
+}
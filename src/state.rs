@@ -0,0 +1,75 @@
+use reqwest::blocking::Client;
+
+use rocket::{Outcome, State};
+use rocket::config::Config;
+use rocket::request::{self, FromRequest, Request};
+
+use crate::calibration::Calibration;
+use crate::deadline::Deadline;
+use crate::health::ProviderHealth;
+use crate::offline::OfflineRouter;
+use crate::plausibility::Plausibility;
+use crate::policy::Policy;
+use crate::pool::OsrmPool;
+use crate::profiles::Profiles;
+use crate::regions::Regions;
+use crate::service::Context;
+use crate::shadow::Shadow;
+use crate::templated::HttpProviders;
+use crate::traffic::Traffic;
+
+pub struct LocalConfig(pub Config);
+
+// Managed state used by the routing handlers, fetched as one request guard.
+pub struct AppState<'r> {
+    pub config: State<'r, LocalConfig>,
+    pub policy: State<'r, Policy>,
+    pub health: State<'r, ProviderHealth>,
+    pub plausibility: State<'r, Plausibility>,
+    pub shadow: State<'r, Shadow>,
+    pub pool: State<'r, OsrmPool>,
+    pub regions: State<'r, Regions>,
+    pub profiles: State<'r, Profiles>,
+    pub traffic: State<'r, Traffic>,
+    pub calibration: State<'r, Calibration>,
+    pub offline: State<'r, OfflineRouter>,
+    pub http_providers: State<'r, HttpProviders>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AppState<'r> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(AppState {
+            config: request.guard::<State<LocalConfig>>()?,
+            policy: request.guard::<State<Policy>>()?,
+            health: request.guard::<State<ProviderHealth>>()?,
+            plausibility: request.guard::<State<Plausibility>>()?,
+            shadow: request.guard::<State<Shadow>>()?,
+            pool: request.guard::<State<OsrmPool>>()?,
+            regions: request.guard::<State<Regions>>()?,
+            profiles: request.guard::<State<Profiles>>()?,
+            traffic: request.guard::<State<Traffic>>()?,
+            calibration: request.guard::<State<Calibration>>()?,
+            offline: request.guard::<State<OfflineRouter>>()?,
+            http_providers: request.guard::<State<HttpProviders>>()?,
+        })
+    }
+}
+
+impl<'r> AppState<'r> {
+    pub fn context<'a>(&'a self, client: &'a Client, deadline: &'a Deadline) -> Context<'a> {
+        Context {
+            client,
+            deadline,
+            policy: self.policy.inner(),
+            health: self.health.inner(),
+            plausibility: self.plausibility.inner(),
+            shadow: self.shadow.inner(),
+            pool: self.pool.inner(),
+            traffic: self.traffic.inner(),
+            calibration: self.calibration.inner(),
+            offline: self.offline.inner(),
+        }
+    }
+}
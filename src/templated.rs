@@ -0,0 +1,265 @@
+use reqwest::blocking::Client;
+
+use rocket::config::{Config, Table};
+use rocket::http::Status;
+use rocket::response::status;
+use rocket_contrib::json::JsonValue;
+
+use crate::deadline::Deadline;
+use crate::options::RouteOptions;
+use crate::service::SOURCES;
+use crate::utils;
+
+// A provider described in http_providers, its url and body are templates
+// and the response is read with JSON pointers.
+//
+// Placeholders: {boundary} (lng,lat;...), {points} (lat,lng;...),
+// {coordinates} ([[lng, lat], ...]), {origin_lat}, {origin_lng},
+// {destination_lat}, {destination_lng}, {profile} and {alternatives}.
+// Values are percent encoded in the url and JSON escaped in the body.
+#[derive(Debug, Clone)]
+pub struct Templated {
+    pub name: String,
+    method: String,
+    url: String,
+    body: Option<String>,
+    headers: Vec<(String, String)>,
+    // pointer to the routes array, empty when the response is one route
+    routes: String,
+    distance: String,
+    duration: String,
+    geometry: String,
+    // 0 reads the geometry as [[lng, lat], ...]
+    geometry_precision: u32,
+    distance_scale: f64,
+    duration_scale: f64,
+    // supported wrapper profiles, empty supports every profile
+    profiles: Vec<String>,
+}
+
+// The http_providers of the config, parsed once at launch.
+pub struct HttpProviders {
+    pub providers: Vec<Templated>,
+}
+
+impl HttpProviders {
+    // http_providers = { partner = { url = "...", distance = "/distance", ... } }
+    pub fn from_config(config: &Config) -> Self {
+        let mut providers: Vec<Templated> = Vec::new();
+        if let Ok(table) = config.get_table("http_providers") {
+            for (name, provider) in table {
+                let provider = match provider.as_table() {
+                    Some(t) => Templated::from_table(name, t),
+                    None => Err(format!("http_providers.{}: must be a table", name)),
+                };
+
+                match provider {
+                    Ok(p) => providers.push(p),
+                    Err(err) => println!("skip {}", err),
+                }
+            }
+        }
+
+        Self {
+            providers,
+        }
+    }
+}
+
+impl Templated {
+    fn from_table(name: &str, table: &Table) -> Result<Self, String> {
+        if SOURCES.contains(&name) {
+            return Err(format!("http_providers.{}: name is taken by a built in source", name));
+        }
+
+        let get_str = |key: &str| table.get(key).and_then(|v| v.as_str()).map(|v| v.to_string());
+        let get_float = |key: &str| table.get(key).and_then(|v| v.as_float().or_else(|| v.as_integer().map(|i| i as f64)));
+
+        let url = get_str("url").ok_or_else(|| format!("http_providers.{}: missing url", name))?;
+        let distance = get_str("distance").ok_or_else(|| format!("http_providers.{}: missing distance pointer", name))?;
+        let duration = get_str("duration").ok_or_else(|| format!("http_providers.{}: missing duration pointer", name))?;
+        let geometry = get_str("geometry").ok_or_else(|| format!("http_providers.{}: missing geometry pointer", name))?;
+
+        let routes = get_str("routes").unwrap_or_default();
+        for (key, pointer) in [("routes", &routes), ("distance", &distance), ("duration", &duration), ("geometry", &geometry)].iter() {
+            if !pointer.starts_with('/') && !(*key == "routes" && pointer.is_empty()) {
+                return Err(format!("http_providers.{}: {} must be a JSON pointer starting with /", name, key));
+            }
+        }
+
+        let geometry_precision = match table.get("geometry_precision").and_then(|p| p.as_integer()) {
+            Some(p) if (0..=u32::MAX as i64).contains(&p) => p as u32,
+            Some(p) => return Err(format!("http_providers.{}: invalid geometry_precision {}", name, p)),
+            None => 5,
+        };
+
+        let method = get_str("method").unwrap_or_else(|| String::from("GET")).to_uppercase();
+        if method != "GET" && method != "POST" {
+            return Err(format!("http_providers.{}: method must be GET or POST", name));
+        }
+
+        let headers = match table.get("headers").and_then(|h| h.as_table()) {
+            Some(h) => h.iter()
+                .filter_map(|(k, v)| v.as_str().map(|v| (k.to_string(), v.to_string())))
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let profiles = match table.get("profiles").and_then(|p| p.as_array()) {
+            Some(p) => p.iter().filter_map(|p| p.as_str()).map(|p| p.to_string()).collect(),
+            None => Vec::new(),
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            method,
+            url,
+            body: get_str("body"),
+            headers,
+            routes,
+            distance,
+            duration,
+            geometry,
+            geometry_precision,
+            distance_scale: get_float("distance_scale").unwrap_or(1.0),
+            duration_scale: get_float("duration_scale").unwrap_or(1.0),
+            profiles,
+        })
+    }
+
+    fn render(&self, template: &str, boundary: &str, profile: &str, alternatives: bool, escape: fn(&str) -> String) -> String {
+        let waypoints = utils::get_waypoints(boundary);
+        let lng_lats: Vec<String> = waypoints.iter().map(|p| format!("{},{}", p.x(), p.y())).collect();
+        let points: Vec<String> = waypoints.iter().map(|p| format!("{},{}", p.y(), p.x())).collect();
+        let coordinates: Vec<String> = waypoints.iter().map(|p| format!("[{}, {}]", p.x(), p.y())).collect();
+        let (origin, destination) = match (waypoints.first(), waypoints.last()) {
+            (Some(o), Some(d)) => ((o.y(), o.x()), (d.y(), d.x())),
+            _ => ((0.0, 0.0), (0.0, 0.0)),
+        };
+
+        template
+            .replace("{boundary}", escape(lng_lats.join(";").as_str()).as_str())
+            .replace("{points}", escape(points.join(";").as_str()).as_str())
+            .replace("{coordinates}", escape(format!("[{}]", coordinates.join(", ")).as_str()).as_str())
+            .replace("{origin_lat}", origin.0.to_string().as_str())
+            .replace("{origin_lng}", origin.1.to_string().as_str())
+            .replace("{destination_lat}", destination.0.to_string().as_str())
+            .replace("{destination_lng}", destination.1.to_string().as_str())
+            .replace("{profile}", escape(profile).as_str())
+            .replace("{alternatives}", alternatives.to_string().as_str())
+    }
+
+    fn route(&self, route: &serde_json::Value) -> Option<serde_json::Value> {
+        let distance = route.pointer(self.distance.as_str())?.as_f64()? * self.distance_scale;
+        let duration = route.pointer(self.duration.as_str())?.as_f64()? * self.duration_scale;
+        let geometry = route.pointer(self.geometry.as_str())?;
+        let geometry = match self.geometry_precision {
+            0 => {
+                let coordinates: Vec<(f64, f64)> = geometry.as_array()?.iter()
+                    .filter_map(|c| Some((c[1].as_f64()?, c[0].as_f64()?)))
+                    .collect();
+                utils::encode_lat_lngs(&coordinates)
+            },
+            5 => geometry.as_str()?.to_string(),
+            precision => utils::decode_geometries(&[geometry.as_str()?], precision),
+        };
+
+        Some(json!({
+            "distance": distance.round() as u64,
+            "duration": duration.round() as u64,
+            "geometry": geometry,
+        }).into())
+    }
+
+    pub fn call(&self, boundary: &str, profile: &str, options: &RouteOptions, client: &Client, deadline: &Deadline) -> status::Custom<JsonValue> {
+        if !self.profiles.is_empty() && !self.profiles.iter().any(|p| p == profile) {
+            return utils::unsupported(self.name.as_str(), format!("profile {}", profile).as_str());
+        }
+
+        // templates have no way to express routing options
+        if let Some(feature) = options.unsupported_by("templated") {
+            return utils::unsupported(self.name.as_str(), feature);
+        }
+
+        let timeout = match deadline.next_timeout() {
+            Some(t) => t,
+            None => return utils::deadline_exceeded(),
+        };
+
+        let url = self.render(self.url.as_str(), boundary, profile, options.alternatives, url_escape);
+        let mut req = match self.method.as_str() {
+            "POST" => client.post(url.as_str()),
+            _ => client.get(url.as_str()),
+        };
+
+        if let Some(body) = &self.body {
+            req = req
+                .header("content-type", "application/json")
+                .body(self.render(body.as_str(), boundary, profile, options.alternatives, json_escape));
+        }
+
+        for (name, value) in self.headers.iter() {
+            req = req.header(name.as_str(), value.as_str());
+        }
+
+        let res = match req.timeout(timeout).send() {
+            Ok(r) => r,
+            Err(err) => {
+                println!("call {} fail: {}", self.name, utils::without_url(&err));
+                return utils::format_response("request fail", Status::BadRequest)
+            }
+        };
+
+        if res.status().as_u16() >= 400 {
+            println!("{} responded {}", self.name, res.status());
+            return utils::format_response("request fail", Status::BadRequest)
+        }
+
+        let body = match res.json::<serde_json::Value>() {
+            Ok(b) => b,
+            Err(err) => {
+                println!("parse {} response fail: {}", self.name, utils::without_url(&err));
+                return utils::format_response("parse response fail", Status::BadRequest)
+            }
+        };
+
+        let found: Vec<&serde_json::Value> = if self.routes.is_empty() {
+            vec![&body]
+        } else {
+            body.pointer(self.routes.as_str()).and_then(|r| r.as_array()).map(|r| r.iter().collect()).unwrap_or_default()
+        };
+
+        let routes: Vec<serde_json::Value> = found.into_iter().filter_map(|r| self.route(r)).collect();
+        if routes.is_empty() {
+            return utils::format_response("no route found", Status::BadRequest)
+        }
+
+        status::Custom(Status::Ok, utils::format_routes(routes, self.name.as_str()))
+    }
+}
+
+// keeps the separators of {boundary} and {points} as they are
+fn url_escape(value: &str) -> String {
+    value.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b',' | b';' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+// the inside of a JSON string, templates put their own quotes
+fn json_escape(value: &str) -> String {
+    let quoted = serde_json::Value::from(value).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_placeholder_values() {
+        assert_eq!(url_escape("105.8,21;105.9,21.1"), "105.8,21;105.9,21.1");
+        assert_eq!(url_escape("car&key=x y"), "car%26key%3Dx%20y");
+        assert_eq!(json_escape("car\", \"x\": \"y"), "car\\\", \\\"x\\\": \\\"y");
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use rocket::config::{Config, Value};
+use rocket::response::status;
+use rocket_contrib::json::JsonValue;
+
+use crate::utils;
+
+// A speed factor for some hours of some days, hours wrap around midnight
+// like the policy hours.
+struct Window {
+    // 1 = Monday .. 7 = Sunday
+    days: Vec<u32>,
+    from: u32,
+    to: u32,
+    speed_factor: f64,
+}
+
+impl Window {
+    fn from_value(value: &Value) -> Option<Self> {
+        let table = value.as_table()?;
+        let days = match table.get("days").and_then(|d| d.as_array()) {
+            Some(d) => d.iter().filter_map(|d| d.as_integer()).map(|d| d as u32).collect(),
+            None => (1..=7).collect(),
+        };
+
+        let speed_factor = table.get("speed_factor").and_then(|f| f.as_float().or_else(|| f.as_integer().map(|i| i as f64)))?;
+        if speed_factor <= 0.0 {
+            return None;
+        }
+
+        Some(Self {
+            days,
+            from: table.get("from")?.as_integer()? as u32,
+            to: table.get("to")?.as_integer()? as u32,
+            speed_factor,
+        })
+    }
+
+    fn matches(&self, weekday: u32, hour: u32) -> bool {
+        if self.from <= self.to {
+            return self.days.contains(&weekday) && hour >= self.from && hour < self.to;
+        }
+
+        // a window past midnight belongs to the day it starts on
+        let previous = if weekday == 1 { 7 } else { weekday - 1 };
+        (self.days.contains(&weekday) && hour >= self.from) || (self.days.contains(&previous) && hour < self.to)
+    }
+}
+
+// Scales free-flow durations of OSRM, Vietbando and estimates by hour-of-week speed
+// factors per region, "default" applies outside configured regions.
+pub struct Traffic {
+    windows: HashMap<String, Vec<Window>>,
+    utc_offset: i64,
+}
+
+impl Traffic {
+    // traffic_speed_factors = { hanoi = [{ days = [1, 2, 3, 4, 5], from = 7, to = 9, speed_factor = 0.6 }], ... }
+    pub fn from_config(config: &Config) -> Self {
+        let mut windows: HashMap<String, Vec<Window>> = HashMap::new();
+        if let Ok(table) = config.get_table("traffic_speed_factors") {
+            for (region, entries) in table {
+                let entries = match entries.as_array() {
+                    Some(e) => e,
+                    None => {
+                        println!("skip traffic_speed_factors.{}: must be an array", region);
+                        continue;
+                    }
+                };
+
+                let mut parsed: Vec<Window> = Vec::new();
+                for entry in entries {
+                    match Window::from_value(entry) {
+                        Some(w) => parsed.push(w),
+                        None => println!("skip traffic_speed_factors.{} entry: expected from, to and a positive speed_factor", region),
+                    }
+                }
+                windows.insert(region.to_string(), parsed);
+            }
+        }
+
+        let utc_offset = match config.get_int("utc_offset") {
+            Ok(o) => o,
+            _ => 7,
+        };
+
+        Self {
+            windows,
+            utc_offset,
+        }
+    }
+
+    fn speed_factor(&self, region: Option<&str>, weekday: u32, hour: u32) -> Option<f64> {
+        let windows = region
+            .and_then(|r| self.windows.get(r))
+            .or_else(|| self.windows.get("default"))?;
+
+        windows.iter().find(|w| w.matches(weekday, hour)).map(|w| w.speed_factor)
+    }
+
+    // Divides route durations by the speed factor at departure, depart_at
+    // defaults to now.
+    pub fn apply(&self, source: &str, region: Option<&str>, depart_at: Option<u64>, mut result: status::Custom<JsonValue>) -> status::Custom<JsonValue> {
+        if result.0.code >= 400 || (source != "osrm" && source != "vietbando" && source != "estimate") {
+            return result;
+        }
+
+        let (weekday, hour) = hour_of_week(depart_at.unwrap_or_else(utils::now_secs), self.utc_offset);
+        let speed_factor = match self.speed_factor(region, weekday, hour) {
+            Some(f) => f,
+            None => return result,
+        };
+
+        if let Some(routes) = result.1["routes"].as_array_mut() {
+            for route in routes.iter_mut() {
+                if let Some(duration) = route["duration"].as_f64() {
+                    route["duration"] = (duration / speed_factor).round().into();
+                }
+                scale_parts(route, 1.0 / speed_factor);
+            }
+        }
+
+        result.1["traffic"] = json!({
+            "speed_factor": speed_factor,
+            "weekday": weekday,
+            "hour": hour,
+        }).into();
+        result
+    }
+}
+
+// Scales the leg, step and annotation durations of a route so they keep
+// adding up to its duration, to OSRM's 0.1 s.
+pub fn scale_parts(route: &mut serde_json::Value, factor: f64) {
+    let scale = |value: &mut serde_json::Value| {
+        if let Some(d) = value.as_f64() {
+            *value = ((d * factor * 10.0).round() / 10.0).into();
+        }
+    };
+
+    if let Some(steps) = route["steps"].as_array_mut() {
+        steps.iter_mut().for_each(|s| scale(&mut s["duration"]));
+    }
+
+    if let Some(legs) = route["legs"].as_array_mut() {
+        for leg in legs.iter_mut() {
+            scale(&mut leg["duration"]);
+            if let Some(steps) = leg["steps"].as_array_mut() {
+                steps.iter_mut().for_each(|s| scale(&mut s["duration"]));
+            }
+
+            if let Some(durations) = leg["annotation"]["duration"].as_array_mut() {
+                durations.iter_mut().for_each(scale);
+            }
+        }
+    }
+}
+
+// local weekday (1 = Monday) and hour of a unix timestamp
+fn hour_of_week(timestamp: u64, utc_offset: i64) -> (u32, u32) {
+    let local = timestamp as i64 + utc_offset * 3600;
+    let days = local.div_euclid(86400);
+    let hour = local.rem_euclid(86400) / 3600;
+
+    // 1970-01-01 was a Thursday
+    ((days + 3).rem_euclid(7) as u32 + 1, hour as u32)
+}
@@ -1,3 +1,5 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use reqwest::Error;
 
 use rocket::http::Status;
@@ -5,7 +7,7 @@ use rocket::response::status;
 use rocket_contrib::json::JsonValue;
 
 
-use geo::{Point, vincenty_distance::VincentyDistance};
+use geo::{Coordinate, Point, vincenty_distance::VincentyDistance};
 use polyline::{decode_polyline, encode_coordinates};
 
 use crate::model::Location;
@@ -59,10 +61,76 @@ fn get_lat_lng(point: &str) -> (f64, f64) {
 
 fn get_point(point: &str) -> Point<f64> {
     let (lat, lng) = get_lat_lng(point);
-    Point::<f64>::new(lat, lng)
+    Point::<f64>::new(lng, lat)
+}
+
+// waypoints of a boundary as points with x = lng, y = lat
+pub fn get_waypoints(boundary: &str) -> Vec<Point<f64>> {
+    let mut points: Vec<Point<f64>> = Vec::new();
+    for point in boundary.split(";") {
+        points.push(get_point(point));
+    }
+
+    points
+}
+
+// a single "lng,lat", None unless both parse and are in range
+pub fn parse_coordinate(coordinate: &str) -> Option<Point<f64>> {
+    let parts: Vec<&str> = coordinate.split(",").collect();
+    if parts.len() != 2 {
+        return None;
+    }
+
+    let lng = parts[0].trim().parse::<f64>().ok()?;
+    let lat = parts[1].trim().parse::<f64>().ok()?;
+    if !(-180.0..=180.0).contains(&lng) || !(-90.0..=90.0).contains(&lat) {
+        return None;
+    }
+    Some(Point::new(lng, lat))
+}
+
+pub fn now_nanos() -> u128 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_nanos(),
+        Err(_) => 0,
+    }
+}
+
+pub fn now_secs() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+// UTC date time of a unix timestamp as RFC 3339
+pub fn format_rfc3339(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+
+    // civil date from days since 1970-01-01
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, seconds / 3600, seconds % 3600 / 60, seconds % 60)
 }
 
-pub fn build_google_url(url: &str, api_key: &str, boundary: &str) -> String {
+pub fn local_hour(utc_offset: i64) -> u32 {
+    hour_at(now_secs(), utc_offset)
+}
+
+pub fn hour_at(timestamp: u64, utc_offset: i64) -> u32 {
+    ((timestamp as i64 / 3600 + utc_offset) % 24 + 24) as u32 % 24
+}
+
+pub fn build_google_url(url: &str, api_key: &str, boundary: &str, params: &str) -> String {
     let mut completed_url = String::from("");
     let mut way_points: Vec<String> = Vec::new();
     let points: Vec<&str> = boundary.split(";").collect();
@@ -83,7 +151,52 @@ pub fn build_google_url(url: &str, api_key: &str, boundary: &str) -> String {
 
     let way_points = way_points.join("|");
     
-    format!("{}?{}&waypoints={}&key={}", url, completed_url, way_points, api_key)
+    format!("{}?{}&waypoints={}&{}&key={}", url, completed_url, way_points, params, api_key)
+}
+
+// point=lat,lng&point=... as GraphHopper expects
+pub fn build_points_query(boundary: &str) -> String {
+    let points: Vec<String> = boundary.split(";")
+        .map(|p| {
+            let (lat, lng) = get_lat_lng(p);
+            format!("point={},{}", lat, lng)
+        })
+        .collect();
+    points.join("&")
+}
+
+// Goong takes the first point as origin and the rest, ";" separated, as
+// destination
+pub fn build_goong_waypoints(boundary: &str) -> String {
+    let points: Vec<String> = boundary.split(";")
+        .map(|p| {
+            let (lat, lng) = get_lat_lng(p);
+            format!("{},{}", lat, lng)
+        })
+        .collect();
+
+    match points.split_first() {
+        Some((origin, destinations)) => format!("origin={}&destination={}", origin, destinations.join(";")),
+        None => String::from(""),
+    }
+}
+
+// origin, via and destination as HERE expects
+pub fn build_here_waypoints(boundary: &str) -> String {
+    let points: Vec<&str> = boundary.split(";").collect();
+    let mut query: Vec<String> = Vec::new();
+    for (i, point) in points.iter().enumerate() {
+        let (lat, lng) = get_lat_lng(point);
+        let name = if i == 0 {
+            "origin"
+        } else if i == points.len() - 1 {
+            "destination"
+        } else {
+            "via"
+        };
+        query.push(format!("{}={},{}", name, lat, lng));
+    }
+    query.join("&")
 }
 
 pub fn get_location(boundary: &str) -> Vec<Location> {
@@ -102,7 +215,7 @@ pub fn format_response_third_party(geometry: &str, distance: u64, duration: u64,
         "routes": [
             {
                 "distance": distance,
-                "duration:": duration,
+                "duration": duration,
                 "geometry": geometry,
             }
         ],
@@ -110,24 +223,76 @@ pub fn format_response_third_party(geometry: &str, distance: u64, duration: u64,
     })
 }
 
-pub fn decode_geometry(g: &str) -> String {
-    match decode_polyline(g, 6) {
-        Ok(lines) => {
-            match encode_coordinates(lines, 5) {
-                Ok(line) => line,
-                Err(err) => {
-                    println!("encode_coordinates fail: {}", err);
-                    String::from("")
-                },
-            }
+// Vietbando and Google routes were first served with a "duration:" key, it
+// is kept next to "duration" for the clients still reading it.
+pub fn add_legacy_duration(result: &mut status::Custom<JsonValue>) {
+    if let Some(routes) = result.1["routes"].as_array_mut() {
+        for route in routes.iter_mut() {
+            route["duration:"] = route["duration"].clone();
+        }
+    }
+}
+
+pub fn encode_lat_lngs(coordinates: &[(f64, f64)]) -> String {
+    let coordinates: Vec<Coordinate<f64>> = coordinates.iter().map(|(lat, lng)| Coordinate { x: *lng, y: *lat }).collect();
+    match encode_coordinates(coordinates, 5) {
+        Ok(line) => line,
+        Err(err) => {
+            println!("encode_coordinates fail: {}", err);
+            String::from("")
         },
+    }
+}
+
+// normalized response for providers returning several routes
+pub fn format_routes(routes: Vec<serde_json::Value>, source: &str) -> JsonValue {
+    json!({
+        "code": "Ok",
+        "routes": routes,
+        "source": source
+    })
+}
+
+pub fn decode_geometry(g: &str) -> String {
+    decode_geometries(&[g], 6)
+}
+
+// Joins parts encoded at a precision, e.g. the polyline6 legs of a trip,
+// into one polyline5.
+pub fn decode_geometries(parts: &[&str], precision: u32) -> String {
+    let mut coordinates: Vec<Coordinate<f64>> = Vec::new();
+    for part in parts {
+        match decode_polyline(part, precision) {
+            Ok(line) => {
+                // consecutive legs share the waypoint between them
+                let skip = if coordinates.is_empty() { 0 } else { 1 };
+                coordinates.extend(line.0.into_iter().skip(skip));
+            },
+            Err(err) => {
+                println!("decode_polyline fail: {}", err);
+                return String::from("")
+            },
+        }
+    }
+
+    match encode_coordinates(coordinates, 5) {
+        Ok(line) => line,
         Err(err) => {
-            println!("decode_polyline fail: {}", err);
+            println!("encode_coordinates fail: {}", err);
             String::from("")
         },
     }
 }
 
+// one normalized turn instruction, distance in m and duration in s
+pub fn format_step(text: &serde_json::Value, distance: f64, duration: f64) -> serde_json::Value {
+    json!({
+        "text": text,
+        "distance": distance.round() as u64,
+        "duration": duration.round() as u64,
+    }).into()
+}
+
 pub fn format_response(message: &str, status: Status) -> status::Custom<JsonValue> {
     status::Custom(
         status,
@@ -138,7 +303,72 @@ pub fn format_response(message: &str, status: Status) -> status::Custom<JsonValu
     )
 }
 
+// the provider can't express what was asked, the fallback chain moves on
+pub fn unsupported(source: &str, feature: &str) -> status::Custom<JsonValue> {
+    let mut response = format_response(format!("{} does not support {}", source, feature).as_str(), Status::BadRequest);
+    response.1["unsupported"] = serde_json::Value::Bool(true);
+    response
+}
+
+// capability errors and an exhausted request deadline say nothing about
+// the provider's health
+pub fn is_provider_failure(result: &status::Custom<JsonValue>) -> bool {
+    result.0.code >= 400 && result.0 != Status::GatewayTimeout && result.1["unsupported"] != true
+}
+
+pub fn deadline_exceeded() -> status::Custom<JsonValue> {
+    format_response("request deadline exceeded", Status::GatewayTimeout)
+}
+
+// reqwest errors print their url, and some providers take the api key in the query
+pub fn without_url(err: &Error) -> String {
+    let message = err.to_string();
+    match err.url() {
+        Some(url) => message.replace(format!(" for url ({})", url.as_str()).as_str(), ""),
+        None => message,
+    }
+}
+
 pub fn err_response(message: &str, status: Status, e: Error) -> status::Custom<JsonValue> {
-    eprint!("{}: {}", message, e);
+    eprint!("{}: {}", message, without_url(&e));
     format_response(message, status)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_rfc3339_timestamps() {
+        assert_eq!(format_rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_rfc3339(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_rfc3339(1582979696), "2020-02-29T12:34:56Z");
+        assert_eq!(format_rfc3339(1583020800), "2020-03-01T00:00:00Z");
+        assert_eq!(format_rfc3339(1609459199), "2020-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn parses_coordinates_strictly() {
+        assert_eq!(parse_coordinate("105.8342,21.0278"), Some(Point::new(105.8342, 21.0278)));
+        assert_eq!(parse_coordinate("-180, -90"), Some(Point::new(-180.0, -90.0)));
+        assert_eq!(parse_coordinate("105.8342"), None);
+        assert_eq!(parse_coordinate("105.8342,21.0278;105.84,21.02"), None);
+        assert_eq!(parse_coordinate("105.8342,21.0278,5"), None);
+        assert_eq!(parse_coordinate("lng,21.0278"), None);
+        assert_eq!(parse_coordinate("21.0278,105.8342"), None);
+        assert_eq!(parse_coordinate("NaN,21.0278"), None);
+    }
+
+    #[test]
+    fn joins_polyline6_legs_at_their_shared_waypoint() {
+        let a = Coordinate { x: 105.80, y: 21.00 };
+        let b = Coordinate { x: 105.81, y: 21.01 };
+        let c = Coordinate { x: 105.82, y: 21.00 };
+        let first = encode_coordinates(vec![a, b], 6).unwrap();
+        let second = encode_coordinates(vec![b, c], 6).unwrap();
+
+        let joined = decode_geometries(&[first.as_str(), second.as_str()], 6);
+        assert_eq!(joined, encode_coordinates(vec![a, b, c], 5).unwrap());
+        assert_eq!(decode_polyline(joined.as_str(), 5).unwrap().0, vec![a, b, c]);
+    }
+}